@@ -1,3 +1,5 @@
+use std::ops::ControlFlow;
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Grid2D {
     pub x: f64,
@@ -9,13 +11,105 @@ pub struct Points2D {
     pub points: Vec<Grid2D>,
 }
 
+/// A point with `D` coordinates that can be stored in a `KDTree<D>`.
+pub trait Coordinates<const D: usize> {
+    fn coordinates(&self) -> [f64; D];
+}
+
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub struct KDTree {
+#[derive(Debug, Clone, PartialEq)]
+struct Node<const D: usize, T> {
     id: usize,
-    position: Grid2D,
-    left: Option<Box<KDTree>>,
-    right: Option<Box<KDTree>>,
+    item: T,
+    position: [f64; D],
+    axis: usize,
+    split: f64,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+    size: usize,
+    // Nonzero when the subtree is stored contiguously in this many nodes starting
+    // here, so queries scan it linearly as one leaf bucket.
+    bucket: usize,
+    removed: bool,
+}
+
+/// A kd-tree over `D`-dimensional points, each carrying a payload `T`.
+/// By default the payload is the index of the point in the input it was built from.
+///
+/// Nodes live in one contiguous `Vec` and refer to their children by index, so a
+/// tree is built with a single allocation and dropped without walking it.
+///
+/// Every point also has an id, its index in the input the tree was built from.
+/// Removed points are tombstoned and dropped when the tree is compacted.
+///
+/// A node's splitting plane is fixed when the node is created, so points can be
+/// moved with `relocate` without invalidating the subtree below them.
+///
+/// A tree with a periodic box measures its radius and k-nearest queries by
+/// minimum-image distance; range, annulus, region, metric and approximate queries
+/// ignore the box.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct KDTree<const D: usize = 2, T = usize> {
+    nodes: Vec<Node<D, T>>,
+    root: Option<usize>,
+    slots: Vec<Option<usize>>,
+    removed: usize,
+    options: BuildOptions<D>,
+}
+
+/// Parameters of bulk builds, kept by the tree for its later rebuilds.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildOptions<const D: usize = 2> {
+    pub split_policy: SplitPolicy,
+    /// Subtrees of up to this many points are stored as leaf buckets that queries
+    /// scan linearly. A bucket that gains a point by insertion is traversed node by
+    /// node again. At least 1, which disables buckets.
+    pub bucket_size: usize,
+    /// The `(lower, upper)` corners of the periodic box. Axes with both bounds finite
+    /// wrap around with period `upper - lower`; the others stay open. Points and
+    /// query points are expected to lie in the box.
+    pub periodic_box: ([f64; D], [f64; D]),
+}
+
+impl<const D: usize> Default for BuildOptions<D> {
+    fn default() -> Self {
+        Self {
+            split_policy: SplitPolicy::default(),
+            bucket_size: 1,
+            periodic_box: ([f64::NEG_INFINITY; D], [f64::INFINITY; D]),
+        }
+    }
+}
+
+/// How bulk builds and rebuilds choose the splitting plane of each node.
+/// Points added one at a time split on the axis after their parent's.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// Cycle through the axes by depth and split at the median.
+    #[default]
+    RoundRobin,
+    /// Split at the median along the axis in which the points spread the most.
+    MaxSpread,
+    /// Split the longest side of the cell at its midpoint, or at the median of the
+    /// points along it when the midpoint would leave a side empty. Cells stay fat on
+    /// clustered input.
+    SlidingMidpoint,
+}
+
+/// How `neighbor_search_with_distances` arranges its results.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborOrder {
+    /// The order the tree was traversed in, as returned by `neighbor_search`.
+    Traversal,
+    /// Nearest first, ties broken by id.
+    Distance,
+    /// Ascending id, independent of the tree layout.
+    Id,
 }
 
 impl Grid2D {
@@ -32,6 +126,18 @@ impl Grid2D {
     }
 }
 
+impl Coordinates<2> for Grid2D {
+    fn coordinates(&self) -> [f64; 2] {
+        [self.x, self.y]
+    }
+}
+
+impl<const D: usize> Coordinates<D> for [f64; D] {
+    fn coordinates(&self) -> [f64; D] {
+        *self
+    }
+}
+
 impl Points2D {
     #[allow(dead_code)]
     pub fn new() -> Self {
@@ -44,274 +150,3383 @@ impl Points2D {
     }
 }
 
-impl KDTree {
+impl AsRef<[Grid2D]> for Points2D {
+    fn as_ref(&self) -> &[Grid2D] {
+        &self.points
+    }
+}
+
+/// A distance between points, used by the `*_with_metric` queries.
+pub trait Metric<const D: usize> {
+    fn distance(&self, a: &[f64; D], b: &[f64; D]) -> f64;
+
+    /// A lower bound on the distance between two points whose coordinates along
+    /// `axis` differ by `diff`. Used to prune subtrees behind a splitting plane.
+    fn axis_distance(&self, axis: usize, diff: f64) -> f64;
+}
+
+/// The straight-line distance.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Euclidean;
+
+/// The sum of the coordinate differences.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Manhattan;
+
+/// The largest coordinate difference.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chebyshev;
+
+/// The Euclidean distance with each squared coordinate difference scaled by its
+/// weight.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightedEuclidean<const D: usize> {
+    pub weights: [f64; D],
+}
+
+impl<const D: usize> Metric<D> for Euclidean {
+    fn distance(&self, a: &[f64; D], b: &[f64; D]) -> f64 {
+        distance_square(a, b).sqrt()
+    }
+
+    fn axis_distance(&self, _axis: usize, diff: f64) -> f64 {
+        diff.abs()
+    }
+}
+
+impl<const D: usize> Metric<D> for Manhattan {
+    fn distance(&self, a: &[f64; D], b: &[f64; D]) -> f64 {
+        a.iter().zip(b.iter()).map(|(p, q)| (p - q).abs()).sum()
+    }
+
+    fn axis_distance(&self, _axis: usize, diff: f64) -> f64 {
+        diff.abs()
+    }
+}
+
+impl<const D: usize> Metric<D> for Chebyshev {
+    fn distance(&self, a: &[f64; D], b: &[f64; D]) -> f64 {
+        a.iter()
+            .zip(b.iter())
+            .map(|(p, q)| (p - q).abs())
+            .fold(0.0, f64::max)
+    }
+
+    fn axis_distance(&self, _axis: usize, diff: f64) -> f64 {
+        diff.abs()
+    }
+}
+
+impl<const D: usize> WeightedEuclidean<D> {
+    #[allow(dead_code)]
+    pub fn new(weights: [f64; D]) -> Self {
+        assert!(weights.iter().all(|&w| w > 0.0));
+        WeightedEuclidean { weights }
+    }
+}
+
+impl<const D: usize> Metric<D> for WeightedEuclidean<D> {
+    fn distance(&self, a: &[f64; D], b: &[f64; D]) -> f64 {
+        a.iter()
+            .zip(b.iter())
+            .zip(self.weights.iter())
+            .map(|((p, q), w)| w * (p - q) * (p - q))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    fn axis_distance(&self, axis: usize, diff: f64) -> f64 {
+        self.weights[axis].sqrt() * diff.abs()
+    }
+}
+
+/// Where an axis-aligned box lies relative to a `Region`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Containment {
+    Inside,
+    Outside,
+    Straddling,
+}
+
+/// A region of space for `region_query`. Points on the boundary are inside.
+pub trait Region<const D: usize> {
+    fn contains(&self, point: &[f64; D]) -> bool;
+
+    /// Classifies the box `[lower, upper]`, whose bounds may be infinite.
+    /// `Straddling` is always a safe answer; the other two let queries prune.
+    fn classify(&self, lower: &[f64; D], upper: &[f64; D]) -> Containment;
+}
+
+/// The points `p` with `normal . p <= offset`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HalfPlane {
+    pub normal: Grid2D,
+    pub offset: f64,
+}
+
+/// A closed disk.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disk {
+    pub center: Grid2D,
+    pub radius: f64,
+}
+
+/// A convex polygon, stored as the intersection of the half-planes of its edges.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvexPolygon {
+    edges: Vec<HalfPlane>,
+    lower: [f64; 2],
+    upper: [f64; 2],
+}
+
+impl HalfPlane {
+    #[allow(dead_code)]
+    pub fn new(normal: Grid2D, offset: f64) -> Self {
+        HalfPlane { normal, offset }
+    }
+
+    // The smallest and largest value of `normal . p` over the box.
+    fn extent(&self, lower: &[f64; 2], upper: &[f64; 2]) -> (f64, f64) {
+        let mut extent = (0.0, 0.0);
+        for (i, n) in [self.normal.x, self.normal.y].into_iter().enumerate() {
+            if n > 0.0 {
+                extent.0 += n * lower[i];
+                extent.1 += n * upper[i];
+            } else if n < 0.0 {
+                extent.0 += n * upper[i];
+                extent.1 += n * lower[i];
+            }
+        }
+        extent
+    }
+}
+
+impl Region<2> for HalfPlane {
+    fn contains(&self, point: &[f64; 2]) -> bool {
+        self.normal.x * point[0] + self.normal.y * point[1] <= self.offset
+    }
+
+    fn classify(&self, lower: &[f64; 2], upper: &[f64; 2]) -> Containment {
+        let (min, max) = self.extent(lower, upper);
+        if max <= self.offset {
+            Containment::Inside
+        } else if min > self.offset {
+            Containment::Outside
+        } else {
+            Containment::Straddling
+        }
+    }
+}
+
+impl Disk {
+    #[allow(dead_code)]
+    pub fn new(center: Grid2D, radius: f64) -> Self {
+        Disk { center, radius }
+    }
+}
+
+impl Region<2> for Disk {
+    fn contains(&self, point: &[f64; 2]) -> bool {
+        distance_square(&self.center.coordinates(), point) <= self.radius * self.radius
+    }
+
+    fn classify(&self, lower: &[f64; 2], upper: &[f64; 2]) -> Containment {
+        let c = self.center.coordinates();
+        let mut near = 0.0;
+        let mut far = 0.0;
+        for i in 0..2 {
+            let d_near = (lower[i] - c[i]).max(c[i] - upper[i]).max(0.0);
+            let d_far = (c[i] - lower[i]).max(upper[i] - c[i]);
+            near += d_near * d_near;
+            far += d_far * d_far;
+        }
+        let r2 = self.radius * self.radius;
+        if far <= r2 {
+            Containment::Inside
+        } else if near > r2 {
+            Containment::Outside
+        } else {
+            Containment::Straddling
+        }
+    }
+}
+
+impl ConvexPolygon {
+    /// Builds the polygon from its vertices in order, either way around.
+    #[allow(dead_code)]
+    pub fn new(vertices: &[Grid2D]) -> Self {
+        assert!(vertices.len() >= 3);
+        let n = vertices.len();
+        let twice_area: f64 = (0..n)
+            .map(|i| {
+                let (a, b) = (&vertices[i], &vertices[(i + 1) % n]);
+                a.x * b.y - b.x * a.y
+            })
+            .sum();
+
+        let mut edges = vec![];
+        let mut lower = [f64::INFINITY; 2];
+        let mut upper = [f64::NEG_INFINITY; 2];
+        for i in 0..n {
+            let (a, b) = (&vertices[i], &vertices[(i + 1) % n]);
+            // The outward normal for counter-clockwise vertices.
+            let mut normal = Grid2D::new(b.y - a.y, a.x - b.x);
+            if twice_area < 0.0 {
+                normal = Grid2D::new(-normal.x, -normal.y);
+            }
+            let offset = normal.x * a.x + normal.y * a.y;
+            edges.push(HalfPlane::new(normal, offset));
+
+            lower = [lower[0].min(a.x), lower[1].min(a.y)];
+            upper = [upper[0].max(a.x), upper[1].max(a.y)];
+        }
+        ConvexPolygon {
+            edges,
+            lower,
+            upper,
+        }
+    }
+}
+
+impl Region<2> for ConvexPolygon {
+    fn contains(&self, point: &[f64; 2]) -> bool {
+        self.edges.iter().all(|edge| edge.contains(point))
+    }
+
+    fn classify(&self, lower: &[f64; 2], upper: &[f64; 2]) -> Containment {
+        if (0..2).any(|i| upper[i] < self.lower[i] || self.upper[i] < lower[i]) {
+            return Containment::Outside;
+        }
+        let mut containment = Containment::Inside;
+        for edge in &self.edges {
+            match edge.classify(lower, upper) {
+                Containment::Outside => return Containment::Outside,
+                Containment::Straddling => containment = Containment::Straddling,
+                Containment::Inside => {}
+            }
+        }
+        containment
+    }
+}
+
+// `relocate` rebuilds the tree once a point ends up deeper than this many times log2(n).
+const MAX_DEPTH_FACTOR: f64 = 3.0;
+
+// `insert_with_item` rebuilds a subtree once one child holds more than this share of it.
+const SCAPEGOAT_ALPHA: f64 = 0.7;
+
+// Lower and upper corner of the region covered by a subtree.
+type Cell<const D: usize> = ([f64; D], [f64; D]);
+
+// Position, id and payload of a point waiting to be placed by a bulk build.
+type Entry<const D: usize, T> = ([f64; D], usize, Option<T>);
+
+fn bounding_box<const D: usize, T>(points: &[Entry<D, T>]) -> Cell<D> {
+    let mut cell = ([f64::INFINITY; D], [f64::NEG_INFINITY; D]);
+    for (position, _, _) in points {
+        for (i, &p) in position.iter().enumerate() {
+            cell.0[i] = cell.0[i].min(p);
+            cell.1[i] = cell.1[i].max(p);
+        }
+    }
+    cell
+}
+
+fn widest_axis<const D: usize>(cell: &Cell<D>) -> usize {
+    (0..D)
+        .max_by(|&a, &b| {
+            let width = |i: usize| cell.1[i] - cell.0[i];
+            width(a).total_cmp(&width(b)).then(b.cmp(&a))
+        })
+        .unwrap()
+}
+
+// Splits `cell` by the plane at `split` across `axis` into (left, right).
+fn split_cell<const D: usize>(cell: Cell<D>, axis: usize, split: f64) -> (Cell<D>, Cell<D>) {
+    let mut left_cell = cell;
+    let mut right_cell = cell;
+    left_cell.1[axis] = split;
+    right_cell.0[axis] = split;
+    (left_cell, right_cell)
+}
+
+// A point seen from `x` at `shift` is seen from the returned point unshifted.
+fn unshift<const D: usize>(x: &[f64; D], shift: &[i32; D], period: &[f64; D]) -> [f64; D] {
+    let mut image = *x;
+    for axis in 0..D {
+        if shift[axis] != 0 {
+            image[axis] -= shift[axis] as f64 * period[axis];
+        }
+    }
+    image
+}
+
+fn distance_square<const D: usize>(a: &[f64; D], b: &[f64; D]) -> f64 {
+    a.iter().zip(b.iter()).map(|(p, q)| (p - q) * (p - q)).sum()
+}
+
+// Entries `TraversalStack` keeps inline before it spills into the heap.
+const INLINE_STACK_LEN: usize = 64;
+
+// A LIFO stack for walking the tree without recursion. A walk that pushes at most two
+// children per popped node holds at most depth + 2 entries, so on any tree shallower
+// than `INLINE_STACK_LEN` it lives on the call stack and never allocates; only
+// degenerate trees spill the remaining entries into a `Vec`.
+struct TraversalStack<E: Copy> {
+    inline: [Option<E>; INLINE_STACK_LEN],
+    len: usize,
+    spill: Vec<E>,
+}
+
+impl<E: Copy> TraversalStack<E> {
+    fn new(first: E) -> Self {
+        let mut stack = Self {
+            inline: [None; INLINE_STACK_LEN],
+            len: 0,
+            spill: Vec::new(),
+        };
+        stack.push(first);
+        stack
+    }
+
+    fn push(&mut self, entry: E) {
+        if self.len < INLINE_STACK_LEN {
+            self.inline[self.len] = Some(entry);
+            self.len += 1;
+        } else {
+            self.spill.push(entry);
+        }
+    }
+
+    fn pop(&mut self) -> Option<E> {
+        if let Some(entry) = self.spill.pop() {
+            return Some(entry);
+        }
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        self.inline[self.len].take()
+    }
+}
+
+impl<const D: usize, T> Node<D, T> {
     #[allow(dead_code)]
-    fn new(vector: &Grid2D, id_: usize) -> Self {
+    fn new(
+        vector: &[f64; D],
+        axis_: usize,
+        split_: f64,
+        parent_: Option<usize>,
+        id_: usize,
+        item_: T,
+    ) -> Self {
         Self {
             id: id_,
-            position: vector.clone(),
+            item: item_,
+            position: *vector,
+            axis: axis_,
+            split: split_,
+            parent: parent_,
             left: None,
             right: None,
+            size: 1,
+            bucket: 0,
+            removed: false,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn is_leaf(&self) -> bool {
+        match (&self.left, &self.right) {
+            (None, None) => true,
+            (Some(_), None) => false,
+            (None, Some(_)) => false,
+            (Some(_), Some(_)) => false,
+        }
+    }
+}
+
+impl<const D: usize, T: Clone> KDTree<D, T> {
+    /// Creates an empty tree.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    #[allow(dead_code)]
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            nodes: Vec::with_capacity(capacity),
+            root: None,
+            slots: Vec::with_capacity(capacity),
+            removed: 0,
+            options: BuildOptions::default(),
+        }
+    }
+
+    /// Makes the tree periodic in the box from `lower` to `upper`; see
+    /// `BuildOptions::periodic_box`. The box is kept across rebuilds.
+    #[allow(dead_code)]
+    pub fn with_periodic_box(
+        mut self,
+        lower: &impl Coordinates<D>,
+        upper: &impl Coordinates<D>,
+    ) -> Self {
+        let (lower, upper) = (lower.coordinates(), upper.coordinates());
+        assert!((0..D).all(|axis| lower[axis] < upper[axis]));
+        self.options.periodic_box = (lower, upper);
+        self
+    }
+
+    // The length of the periodic box along each axis, infinite along open axes.
+    #[allow(dead_code)]
+    fn period(&self) -> [f64; D] {
+        let (lower, upper) = &self.options.periodic_box;
+        let mut period = [f64::INFINITY; D];
+        for (axis, length) in period.iter_mut().enumerate() {
+            if lower[axis].is_finite() && upper[axis].is_finite() {
+                *length = upper[axis] - lower[axis];
+            }
+        }
+        period
+    }
+
+    #[allow(dead_code)]
+    fn is_periodic(&self) -> bool {
+        self.period().iter().any(|length| length.is_finite())
+    }
+
+    // Every combination of -1, 0 and 1 periods along the periodic axes, starting with
+    // the zero shift, without allocating.
+    #[allow(dead_code)]
+    fn image_shifts(&self) -> impl Iterator<Item = [i32; D]> {
+        let period = self.period();
+        (0..3_usize.pow(D as u32)).filter_map(move |code| {
+            let mut shift = [0; D];
+            let mut digits = code;
+            for (axis, s) in shift.iter_mut().enumerate() {
+                *s = [0, -1, 1][digits % 3];
+                digits /= 3;
+                if *s != 0 && period[axis].is_infinite() {
+                    return None;
+                }
+            }
+            Some(shift)
+        })
+    }
+
+    // The squared distance from `image` to the periodic box, which bounds the squared
+    // distance from `image` to every point.
+    #[allow(dead_code)]
+    fn box_gap_square(&self, image: &[f64; D]) -> f64 {
+        let (lower, upper) = &self.options.periodic_box;
+        (0..D)
+            .map(|i| {
+                let d = (lower[i] - image[i]).max(image[i] - upper[i]).max(0.0);
+                d * d
+            })
+            .sum()
+    }
+
+    // The nodes of the subtree under `node` if it is a leaf bucket.
+    #[allow(dead_code)]
+    fn bucket(&self, node: usize) -> Option<&[Node<D, T>]> {
+        match self.nodes[node].bucket {
+            0 => None,
+            len => Some(&self.nodes[node..node + len]),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn push_node(
+        &mut self,
+        point: &[f64; D],
+        axis: usize,
+        split: f64,
+        parent: Option<usize>,
+        id: usize,
+        item: T,
+    ) -> usize {
+        let node = self.nodes.len();
+        self.nodes
+            .push(Node::new(point, axis, split, parent, id, item));
+        if id >= self.slots.len() {
+            self.slots.resize(id + 1, None);
+        }
+        self.slots[id] = Some(node);
+        node
+    }
+
+    #[allow(dead_code)]
+    fn number_of_leaves(&self) -> usize {
+        match self.root {
+            Some(root) => self.count_leaves(root),
+            None => 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn count_leaves(&self, node: usize) -> usize {
+        let mut leaves = 0;
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            match (self.nodes[node].left, self.nodes[node].right) {
+                (None, None) => leaves += 1,
+                (left_node, right_node) => {
+                    stack.extend(left_node);
+                    stack.extend(right_node);
+                }
+            }
+        }
+        leaves
+    }
+
+    #[allow(dead_code)]
+    pub fn neighbor_search(&self, x: &impl Coordinates<D>, radius: f64) -> Vec<T> {
+        let mut near = vec![];
+        if self.is_periodic() {
+            let _ = self.visit_images(&x.coordinates(), radius, &mut |node, _, _| {
+                near.push(node.item.clone());
+                ControlFlow::<()>::Continue(())
+            });
+        } else if let Some(root) = self.root {
+            self.search_points_id(root, &x.coordinates(), radius, &mut near);
+        }
+        near
+    }
+
+    #[allow(dead_code)]
+    fn search_points_id(&self, node: usize, x: &[f64; D], radius: f64, near: &mut Vec<T>) {
+        // Left children are pushed first so that right subtrees are searched first.
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            if let Some(bucket) = self.bucket(node) {
+                for leaf in bucket {
+                    if distance_square(&leaf.position, x).sqrt() < radius && !leaf.removed {
+                        near.push(leaf.item.clone());
+                    }
+                }
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            let axis = current.axis;
+            let r_self = distance_square(&current.position, x).sqrt();
+            if r_self < radius && !current.removed {
+                near.push(current.item.clone());
+            }
+
+            if let Some(left_node) = current.left {
+                if x[axis] - radius <= current.split {
+                    stack.push(left_node);
+                }
+            }
+            if let Some(right_node) = current.right {
+                if current.split <= x[axis] + radius {
+                    stack.push(right_node);
+                }
+            }
+        }
+    }
+
+    /// Calls `visit(item, position, distance_squared)` for every point within `radius`
+    /// of `x`, in the same order as `neighbor_search`, without allocating unless the
+    /// tree is more than 64 levels deep. Returning `ControlFlow::Break` from `visit`
+    /// stops the traversal.
+    #[allow(dead_code)]
+    pub fn for_each_within<B>(
+        &self,
+        x: &impl Coordinates<D>,
+        radius: f64,
+        mut visit: impl FnMut(&T, &[f64; D], f64) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        self.visit_images(&x.coordinates(), radius, &mut |node, r2, _| {
+            visit(&node.item, &node.position, r2)
+        })
+    }
+
+    /// Returns `(item, distance_squared)` for every point within `radius` of `x`,
+    /// arranged according to `order`.
+    #[allow(dead_code)]
+    pub fn neighbor_search_with_distances(
+        &self,
+        x: &impl Coordinates<D>,
+        radius: f64,
+        order: NeighborOrder,
+    ) -> Vec<(T, f64)> {
+        let mut near: Vec<(usize, T, f64)> = vec![];
+        let _ = self.visit_images(&x.coordinates(), radius, &mut |node, r2, _| {
+            near.push((node.id, node.item.clone(), r2));
+            ControlFlow::<()>::Continue(())
+        });
+        match order {
+            NeighborOrder::Traversal => {}
+            NeighborOrder::Distance => {
+                near.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).then(a.0.cmp(&b.0)))
+            }
+            NeighborOrder::Id => near.sort_unstable_by_key(|a| a.0),
+        }
+        near.into_iter().map(|(_, item, r2)| (item, r2)).collect()
+    }
+
+    // Calls `visit(node, distance_squared, shift)` for every point whose nearest image
+    // lies within `radius` of `x`, skipping the shifts whose query image is `radius` or
+    // more from the periodic box. An open tree only has the zero shift.
+    #[allow(dead_code)]
+    fn visit_images<B, F: FnMut(&Node<D, T>, f64, [i32; D]) -> ControlFlow<B>>(
+        &self,
+        x: &[f64; D],
+        radius: f64,
+        visit: &mut F,
+    ) -> ControlFlow<B> {
+        let root = match self.root {
+            Some(root) => root,
+            None => return ControlFlow::Continue(()),
+        };
+        let period = self.period();
+        assert!(period.iter().all(|&length| 2.0 * radius <= length));
+        for shift in self.image_shifts() {
+            let image = unshift(x, &shift, &period);
+            if shift != [0; D] && self.box_gap_square(&image).sqrt() >= radius {
+                continue;
+            }
+            self.visit_within(root, &image, radius, &mut |node, r2| visit(node, r2, shift))?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    #[allow(dead_code)]
+    fn visit_within<B, F: FnMut(&Node<D, T>, f64) -> ControlFlow<B>>(
+        &self,
+        node: usize,
+        x: &[f64; D],
+        radius: f64,
+        visit: &mut F,
+    ) -> ControlFlow<B> {
+        // Left children are pushed first so that right subtrees are searched first.
+        // The stack stays off the heap, as `for_each_within` promises.
+        let mut stack = TraversalStack::new(node);
+        while let Some(node) = stack.pop() {
+            if let Some(bucket) = self.bucket(node) {
+                for leaf in bucket {
+                    let r2 = distance_square(&leaf.position, x);
+                    if r2.sqrt() < radius && !leaf.removed {
+                        visit(leaf, r2)?;
+                    }
+                }
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            let axis = current.axis;
+            let r2_self = distance_square(&current.position, x);
+            if r2_self.sqrt() < radius && !current.removed {
+                visit(current, r2_self)?;
+            }
+
+            if let Some(left_node) = current.left {
+                if x[axis] - radius <= current.split {
+                    stack.push(left_node);
+                }
+            }
+            if let Some(right_node) = current.right {
+                if current.split <= x[axis] + radius {
+                    stack.push(right_node);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns every unordered pair of points closer than `radius` exactly once.
+    ///
+    /// Each pair is `(a, b)` with the id of `a` below the id of `b`, and the pairs
+    /// are sorted by id, so the output does not depend on the tree layout.
+    #[allow(dead_code)]
+    pub fn pairs_within(&self, radius: f64) -> Vec<(T, T)> {
+        let mut pairs = vec![];
+        if self.is_periodic() {
+            // Periodic pairs are found point by point from each point's images.
+            for point in self.nodes.iter().filter(|node| !node.removed) {
+                let _ = self.visit_images(&point.position, radius, &mut |other, _, _| {
+                    if point.id < other.id {
+                        pairs.push((point.id, other.id));
+                    }
+                    ControlFlow::<()>::Continue(())
+                });
+            }
+        } else if let Some(root) = self.root {
+            let cell = ([f64::NEG_INFINITY; D], [f64::INFINITY; D]);
+            self.join_self(root, cell, radius, &mut pairs);
+        }
+        pairs.sort_unstable();
+        pairs
+            .into_iter()
+            .map(|(i, j)| (self.item_of(i).clone(), self.item_of(j).clone()))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn item_of(&self, id: usize) -> &T {
+        &self.nodes[self.slots[id].unwrap()].item
+    }
+
+    // Splits the cell `(lower, upper)` of `node` into the cells of its children.
+    #[allow(dead_code)]
+    fn child_cells(&self, node: usize, cell: Cell<D>) -> (Cell<D>, Cell<D>) {
+        split_cell(cell, self.nodes[node].axis, self.nodes[node].split)
+    }
+
+    // Pushes the id pairs within `radius` between `center` and the subtree of `node`.
+    #[allow(dead_code)]
+    fn join_point(&self, center: usize, node: usize, radius: f64, pairs: &mut Vec<(usize, usize)>) {
+        let point = &self.nodes[center];
+        if point.removed {
+            return;
+        }
+        let _ = self.visit_within(node, &point.position, radius, &mut |other, _| {
+            pairs.push((point.id.min(other.id), point.id.max(other.id)));
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    // Pushes every pair within `radius` whose points both lie in the subtree of `node`.
+    #[allow(dead_code)]
+    fn join_self(&self, node: usize, cell: Cell<D>, radius: f64, pairs: &mut Vec<(usize, usize)>) {
+        let mut stack = vec![(node, cell)];
+        while let Some((node, cell)) = stack.pop() {
+            let current = &self.nodes[node];
+            let (left_cell, right_cell) = self.child_cells(node, cell);
+
+            if let Some(left_node) = current.left {
+                self.join_point(node, left_node, radius, pairs);
+                stack.push((left_node, left_cell));
+            }
+            if let Some(right_node) = current.right {
+                self.join_point(node, right_node, radius, pairs);
+                stack.push((right_node, right_cell));
+            }
+            if let (Some(left_node), Some(right_node)) = (current.left, current.right) {
+                self.join_cross(
+                    (left_node, left_cell),
+                    (right_node, right_cell),
+                    radius,
+                    pairs,
+                );
+            }
+        }
+    }
+
+    // Pushes every pair within `radius` with one point in each of two disjoint
+    // subtrees, given as (node, cell).
+    #[allow(dead_code)]
+    fn join_cross(
+        &self,
+        a: (usize, Cell<D>),
+        b: (usize, Cell<D>),
+        radius: f64,
+        pairs: &mut Vec<(usize, usize)>,
+    ) {
+        let (node_b, cell_b) = b;
+        let mut stack = vec![a];
+        while let Some((node_a, cell_a)) = stack.pop() {
+            let gap = (0..D)
+                .map(|i| {
+                    let d = (cell_b.0[i] - cell_a.1[i])
+                        .max(cell_a.0[i] - cell_b.1[i])
+                        .max(0.0);
+                    d * d
+                })
+                .sum::<f64>()
+                .sqrt();
+            if gap >= radius {
+                continue;
+            }
+
+            self.join_point(node_a, node_b, radius, pairs);
+
+            let (left_cell, right_cell) = self.child_cells(node_a, cell_a);
+            stack.extend(
+                self.nodes[node_a]
+                    .left
+                    .map(|left_node| (left_node, left_cell)),
+            );
+            stack.extend(
+                self.nodes[node_a]
+                    .right
+                    .map(|right_node| (right_node, right_cell)),
+            );
+        }
+    }
+
+    /// Counts the points within `radius` of `x` without allocating, unless the tree
+    /// is more than 64 levels deep.
+    ///
+    /// Subtrees whose cell lies entirely inside the disk are counted from their
+    /// stored size instead of being visited.
+    #[allow(dead_code)]
+    pub fn count_within(&self, x: &impl Coordinates<D>, radius: f64) -> usize {
+        let root = match self.root {
+            Some(root) => root,
+            None => return 0,
+        };
+        // As in `visit_images`, every point has at most one image in range.
+        let (x, period) = (x.coordinates(), self.period());
+        assert!(period.iter().all(|&length| 2.0 * radius <= length));
+        let cell = ([f64::NEG_INFINITY; D], [f64::INFINITY; D]);
+        self.image_shifts()
+            .map(|shift| (shift, unshift(&x, &shift, &period)))
+            .filter(|(shift, image)| *shift == [0; D] || self.box_gap_square(image).sqrt() < radius)
+            .map(|(_, image)| self.count_points(root, &image, radius, cell))
+            .sum()
+    }
+
+    // `cell` bounds the region of `node`.
+    #[allow(dead_code)]
+    fn count_points(&self, node: usize, x: &[f64; D], radius: f64, cell: Cell<D>) -> usize {
+        let mut count = 0;
+        // The stack stays off the heap, as `count_within` promises.
+        let mut stack = TraversalStack::new((node, cell));
+        while let Some((node, (lower, upper))) = stack.pop() {
+            let current = &self.nodes[node];
+            let r_cell = (0..D)
+                .map(|i| {
+                    let d = (x[i] - lower[i]).abs().max((upper[i] - x[i]).abs());
+                    d * d
+                })
+                .sum::<f64>()
+                .sqrt();
+            if r_cell < radius {
+                count += current.size;
+                continue;
+            }
+
+            let r_self = distance_square(&current.position, x).sqrt();
+            if r_self < radius && !current.removed {
+                count += 1;
+            }
+
+            let axis = current.axis;
+            let (left_cell, right_cell) = self.child_cells(node, (lower, upper));
+            if let Some(left_node) = current.left {
+                if x[axis] - radius <= current.split {
+                    stack.push((left_node, left_cell));
+                }
+            }
+            if let Some(right_node) = current.right {
+                if current.split <= x[axis] + radius {
+                    stack.push((right_node, right_cell));
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns every point whose distance `d` from `x` satisfies `r_min <= d < r_max`.
+    ///
+    /// Subtrees whose cell lies entirely inside the inner radius are skipped, as are
+    /// those beyond the outer one.
+    #[allow(dead_code)]
+    pub fn annulus_search(&self, x: &impl Coordinates<D>, r_min: f64, r_max: f64) -> Vec<T> {
+        let mut found = vec![];
+        if let Some(root) = self.root {
+            let cell = ([f64::NEG_INFINITY; D], [f64::INFINITY; D]);
+            self.search_annulus(root, &x.coordinates(), r_min, r_max, cell, &mut found);
+        }
+        found
+    }
+
+    // `cell` bounds the region of `node`.
+    #[allow(dead_code)]
+    fn search_annulus(
+        &self,
+        node: usize,
+        x: &[f64; D],
+        r_min: f64,
+        r_max: f64,
+        cell: Cell<D>,
+        found: &mut Vec<T>,
+    ) {
+        let in_shell = |leaf: &Node<D, T>| {
+            let r = distance_square(&leaf.position, x).sqrt();
+            r_min <= r && r < r_max && !leaf.removed
+        };
+        // Right children are pushed first so that left subtrees are searched first.
+        let mut stack = vec![(node, cell)];
+        while let Some((node, cell)) = stack.pop() {
+            let r_cell = (0..D)
+                .map(|i| {
+                    let d = (x[i] - cell.0[i]).abs().max((cell.1[i] - x[i]).abs());
+                    d * d
+                })
+                .sum::<f64>()
+                .sqrt();
+            if r_cell < r_min {
+                continue;
+            }
+
+            if let Some(bucket) = self.bucket(node) {
+                found.extend(
+                    bucket
+                        .iter()
+                        .filter(|&leaf| in_shell(leaf))
+                        .map(|leaf| leaf.item.clone()),
+                );
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            if in_shell(current) {
+                found.push(current.item.clone());
+            }
+
+            let axis = current.axis;
+            let (left_cell, right_cell) = self.child_cells(node, cell);
+            if let Some(right_node) = current.right {
+                if current.split <= x[axis] + r_max {
+                    stack.push((right_node, right_cell));
+                }
+            }
+            if let Some(left_node) = current.left {
+                if x[axis] - r_max <= current.split {
+                    stack.push((left_node, left_cell));
+                }
+            }
+        }
+    }
+
+    /// Returns every point inside `region`.
+    ///
+    /// Subtrees whose cell `region` classifies as inside are taken whole, and those
+    /// classified as outside are skipped.
+    #[allow(dead_code)]
+    pub fn region_query(&self, region: &dyn Region<D>) -> Vec<T> {
+        let mut found = vec![];
+        if let Some(root) = self.root {
+            let cell = ([f64::NEG_INFINITY; D], [f64::INFINITY; D]);
+            self.search_region(root, region, cell, &mut found);
+        }
+        found
+    }
+
+    // `cell` bounds the region of `node`.
+    #[allow(dead_code)]
+    fn search_region(
+        &self,
+        node: usize,
+        region: &dyn Region<D>,
+        cell: Cell<D>,
+        found: &mut Vec<T>,
+    ) {
+        // Right children are pushed first so that left subtrees are searched first.
+        let mut stack = vec![(node, cell)];
+        while let Some((node, cell)) = stack.pop() {
+            match region.classify(&cell.0, &cell.1) {
+                Containment::Outside => continue,
+                Containment::Inside => {
+                    let mut subtree = vec![node];
+                    while let Some(index) = subtree.pop() {
+                        let current = &self.nodes[index];
+                        if !current.removed {
+                            found.push(current.item.clone());
+                        }
+                        subtree.extend(current.left);
+                        subtree.extend(current.right);
+                    }
+                    continue;
+                }
+                Containment::Straddling => {}
+            }
+
+            if let Some(bucket) = self.bucket(node) {
+                for leaf in bucket {
+                    if region.contains(&leaf.position) && !leaf.removed {
+                        found.push(leaf.item.clone());
+                    }
+                }
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            if region.contains(&current.position) && !current.removed {
+                found.push(current.item.clone());
+            }
+
+            let (left_cell, right_cell) = self.child_cells(node, cell);
+            stack.extend(current.right.map(|right_node| (right_node, right_cell)));
+            stack.extend(current.left.map(|left_node| (left_node, left_cell)));
+        }
+    }
+
+    /// Returns every point inside the axis-aligned box `[min, max]`, bounds included.
+    #[allow(dead_code)]
+    pub fn range_query(&self, min: &impl Coordinates<D>, max: &impl Coordinates<D>) -> Vec<T> {
+        let mut found = vec![];
+        if let Some(root) = self.root {
+            self.search_range(root, &min.coordinates(), &max.coordinates(), &mut found);
+        }
+        found
+    }
+
+    #[allow(dead_code)]
+    fn search_range(&self, node: usize, min: &[f64; D], max: &[f64; D], found: &mut Vec<T>) {
+        let inside = |p: &[f64; D]| (0..D).all(|i| min[i] <= p[i] && p[i] <= max[i]);
+        // Right children are pushed first so that left subtrees are searched first.
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            if let Some(bucket) = self.bucket(node) {
+                for leaf in bucket {
+                    if inside(&leaf.position) && !leaf.removed {
+                        found.push(leaf.item.clone());
+                    }
+                }
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            if inside(&current.position) && !current.removed {
+                found.push(current.item.clone());
+            }
+
+            let axis = current.axis;
+            if let Some(right_node) = current.right {
+                if current.split <= max[axis] {
+                    stack.push(right_node);
+                }
+            }
+            if let Some(left_node) = current.left {
+                if min[axis] <= current.split {
+                    stack.push(left_node);
+                }
+            }
+        }
+    }
+
+    /// Returns the `k` points closest to `x` as `(item, distance)`, nearest first.
+    #[allow(dead_code)]
+    pub fn k_nearest(&self, x: &impl Coordinates<D>, k: usize) -> Vec<(T, f64)> {
+        if self.is_periodic() {
+            return self
+                .periodic_k_nearest(x, k)
+                .into_iter()
+                .map(|(item, r, _)| (item, r))
+                .collect();
+        }
+        let mut best = vec![];
+        if k == 0 {
+            return vec![];
+        }
+        if let Some(root) = self.root {
+            self.search_k_nearest(root, &x.coordinates(), k, &mut best);
+        }
+        best.into_iter()
+            .map(|(node, r2)| (self.nodes[node].item.clone(), r2.sqrt()))
+            .collect()
+    }
+
+    // `best` holds (node, squared distance) sorted ascending, at most `k` entries.
+    #[allow(dead_code)]
+    fn search_k_nearest(&self, node: usize, x: &[f64; D], k: usize, best: &mut Vec<(usize, f64)>) {
+        // Each entry carries a lower bound on the squared distance to its subtree,
+        // checked once the subtrees above it on the stack are done. The far side is
+        // pushed first so that the near side is searched first.
+        let mut stack = vec![(node, 0.0)];
+        while let Some((node, r2_bound)) = stack.pop() {
+            if best.len() == k && best[k - 1].1 <= r2_bound {
+                continue;
+            }
+            let current = &self.nodes[node];
+            if current.bucket > 0 {
+                for leaf in node..node + current.bucket {
+                    self.offer_nearest(leaf, x, k, best);
+                }
+                continue;
+            }
+            self.offer_nearest(node, x, k, best);
+
+            let axis = current.axis;
+            let diff = x[axis] - current.split;
+            let (near_side, far_side) = if diff < 0.0 {
+                (current.left, current.right)
+            } else {
+                (current.right, current.left)
+            };
+
+            stack.extend(far_side.map(|far_node| (far_node, diff * diff)));
+            stack.extend(near_side.map(|near_node| (near_node, 0.0)));
+        }
+    }
+
+    /// Approximate `k_nearest`: the i-th returned neighbor is within `1 + eps` times the
+    /// true i-th nearest distance. Larger `eps` skips more of the tree.
+    #[allow(dead_code)]
+    pub fn k_nearest_approx(&self, x: &impl Coordinates<D>, k: usize, eps: f64) -> Vec<(T, f64)> {
+        assert!(eps >= 0.0);
+        let mut best = vec![];
+        if k == 0 {
+            return vec![];
+        }
+        if let Some(root) = self.root {
+            let slack = (1.0 + eps) * (1.0 + eps);
+            self.search_k_nearest_approx(root, &x.coordinates(), k, slack, &mut best);
+        }
+        best.into_iter()
+            .map(|(node, r2)| (self.nodes[node].item.clone(), r2.sqrt()))
+            .collect()
+    }
+
+    // Each stack entry carries a lower bound on the squared distance from `x` to every
+    // point below its node: the larger of its parent's bound and the squared distance
+    // to the parent's splitting plane. With `slack` at 1 this is the exact search; a
+    // subtree is skipped once its bound times `slack`, (1 + eps)^2, reaches the k-th
+    // best.
+    #[allow(dead_code)]
+    fn search_k_nearest_approx(
+        &self,
+        node: usize,
+        x: &[f64; D],
+        k: usize,
+        slack: f64,
+        best: &mut Vec<(usize, f64)>,
+    ) {
+        let mut stack = vec![(node, 0.0)];
+        while let Some((node, r2_cell)) = stack.pop() {
+            if best.len() == k && best[k - 1].1 <= slack * r2_cell {
+                continue;
+            }
+            let current = &self.nodes[node];
+            if current.bucket > 0 {
+                for leaf in node..node + current.bucket {
+                    self.offer_nearest(leaf, x, k, best);
+                }
+                continue;
+            }
+            self.offer_nearest(node, x, k, best);
+
+            let axis = current.axis;
+            let diff = x[axis] - current.split;
+            let (near_side, far_side) = if diff < 0.0 {
+                (current.left, current.right)
+            } else {
+                (current.right, current.left)
+            };
+
+            stack.extend(far_side.map(|far_node| (far_node, r2_cell.max(diff * diff))));
+            stack.extend(near_side.map(|near_node| (near_node, r2_cell)));
+        }
+    }
+
+    // Adds `node` to `best` of `search_k_nearest` if it is among the `k` nearest so far.
+    #[allow(dead_code)]
+    fn offer_nearest(&self, node: usize, x: &[f64; D], k: usize, best: &mut Vec<(usize, f64)>) {
+        let current = &self.nodes[node];
+        let r2_self = distance_square(&current.position, x);
+        if !current.removed && (best.len() < k || r2_self < best[k - 1].1) {
+            let pos = best.partition_point(|&(_, r2)| r2 <= r2_self);
+            best.insert(pos, (node, r2_self));
+            best.truncate(k);
+        }
+    }
+
+    /// Returns the point closest to `x` as `(item, distance)`.
+    #[allow(dead_code)]
+    pub fn nearest(&self, x: &impl Coordinates<D>) -> Option<(T, f64)> {
+        if self.is_periodic() {
+            return self.k_nearest(x, 1).pop();
+        }
+        let mut best = (None, f64::INFINITY);
+        if let Some(root) = self.root {
+            self.search_nearest(root, &x.coordinates(), &mut best);
+        }
+        best.0.map(|item| (item.clone(), best.1.sqrt()))
+    }
+
+    // `best` holds the (item, squared distance) of the closest point found so far.
+    #[allow(dead_code)]
+    fn search_nearest<'a>(&'a self, node: usize, x: &[f64; D], best: &mut (Option<&'a T>, f64)) {
+        // As in `search_k_nearest`, entries carry a lower bound on the squared distance.
+        let mut stack = TraversalStack::new((node, 0.0));
+        while let Some((node, r2_bound)) = stack.pop() {
+            if best.1 <= r2_bound {
+                continue;
+            }
+            if let Some(bucket) = self.bucket(node) {
+                for leaf in bucket {
+                    let r2 = distance_square(&leaf.position, x);
+                    if r2 < best.1 && !leaf.removed {
+                        *best = (Some(&leaf.item), r2);
+                    }
+                }
+                continue;
+            }
+
+            let current = &self.nodes[node];
+            let r2_self = distance_square(&current.position, x);
+            if r2_self < best.1 && !current.removed {
+                *best = (Some(&current.item), r2_self);
+            }
+
+            let axis = current.axis;
+            let diff = x[axis] - current.split;
+            let (near_side, far_side) = if diff < 0.0 {
+                (current.left, current.right)
+            } else {
+                (current.right, current.left)
+            };
+
+            if let Some(far_node) = far_side {
+                stack.push((far_node, diff * diff));
+            }
+            if let Some(near_node) = near_side {
+                stack.push((near_node, 0.0));
+            }
+        }
+    }
+
+    /// `neighbor_search` with distances measured by `metric`.
+    #[allow(dead_code)]
+    pub fn neighbor_search_with_metric(
+        &self,
+        x: &impl Coordinates<D>,
+        radius: f64,
+        metric: &impl Metric<D>,
+    ) -> Vec<T> {
+        let mut near = vec![];
+        if let Some(root) = self.root {
+            self.search_with_metric(root, &x.coordinates(), radius, metric, &mut near);
+        }
+        near
+    }
+
+    #[allow(dead_code)]
+    fn search_with_metric(
+        &self,
+        node: usize,
+        x: &[f64; D],
+        radius: f64,
+        metric: &impl Metric<D>,
+        near: &mut Vec<T>,
+    ) {
+        // Left children are pushed first so that right subtrees are searched first.
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            let current = &self.nodes[node];
+            let axis = current.axis;
+            if metric.distance(&current.position, x) < radius && !current.removed {
+                near.push(current.item.clone());
+            }
+
+            let diff = x[axis] - current.split;
+            let plane = metric.axis_distance(axis, diff);
+            if let Some(left_node) = current.left {
+                if diff <= 0.0 || plane <= radius {
+                    stack.push(left_node);
+                }
+            }
+            if let Some(right_node) = current.right {
+                if diff >= 0.0 || plane <= radius {
+                    stack.push(right_node);
+                }
+            }
+        }
+    }
+
+    /// `k_nearest` with distances measured by `metric`.
+    #[allow(dead_code)]
+    pub fn k_nearest_with_metric(
+        &self,
+        x: &impl Coordinates<D>,
+        k: usize,
+        metric: &impl Metric<D>,
+    ) -> Vec<(T, f64)> {
+        let mut best = vec![];
+        if k == 0 {
+            return vec![];
+        }
+        if let Some(root) = self.root {
+            self.search_k_nearest_with_metric(root, &x.coordinates(), k, metric, &mut best);
+        }
+        best.into_iter()
+            .map(|(node, r)| (self.nodes[node].item.clone(), r))
+            .collect()
+    }
+
+    // `best` holds (node, distance) sorted ascending, at most `k` entries.
+    #[allow(dead_code)]
+    fn search_k_nearest_with_metric(
+        &self,
+        node: usize,
+        x: &[f64; D],
+        k: usize,
+        metric: &impl Metric<D>,
+        best: &mut Vec<(usize, f64)>,
+    ) {
+        // As in `search_k_nearest`, entries carry a lower bound on the distance.
+        let mut stack = vec![(node, 0.0)];
+        while let Some((node, r_bound)) = stack.pop() {
+            if best.len() == k && best[k - 1].1 <= r_bound {
+                continue;
+            }
+            let current = &self.nodes[node];
+            let r_self = metric.distance(&current.position, x);
+            if !current.removed && (best.len() < k || r_self < best[k - 1].1) {
+                let pos = best.partition_point(|&(_, r)| r <= r_self);
+                best.insert(pos, (node, r_self));
+                best.truncate(k);
+            }
+
+            let axis = current.axis;
+            let diff = x[axis] - current.split;
+            let (near_side, far_side) = if diff < 0.0 {
+                (current.left, current.right)
+            } else {
+                (current.right, current.left)
+            };
+
+            let plane = metric.axis_distance(axis, diff);
+            stack.extend(far_side.map(|far_node| (far_node, plane)));
+            stack.extend(near_side.map(|near_node| (near_node, 0.0)));
+        }
+    }
+
+    /// Returns `(item, shift)` for every point whose nearest periodic image lies
+    /// within `radius` of `x`. That image is at `position + shift * period`.
+    ///
+    /// `radius` may be at most half of each period, so every point has at most one
+    /// image in range. Images are only searched when `x` lies within `radius` of the
+    /// matching side of the box.
+    #[allow(dead_code)]
+    pub fn periodic_neighbor_search(
+        &self,
+        x: &impl Coordinates<D>,
+        radius: f64,
+    ) -> Vec<(T, [i32; D])> {
+        let mut near = vec![];
+        let _ = self.visit_images(&x.coordinates(), radius, &mut |node, _, shift| {
+            near.push((node.item.clone(), shift));
+            ControlFlow::<()>::Continue(())
+        });
+        near
+    }
+
+    /// Returns up to `k` points nearest to `x` under minimum-image distances as
+    /// `(item, distance, shift)`, nearest first. The image is at
+    /// `position + shift * period`.
+    ///
+    /// Images are only searched when `x` lies closer to the matching side of the box
+    /// than the k-th nearest point found so far.
+    #[allow(dead_code)]
+    pub fn periodic_k_nearest(&self, x: &impl Coordinates<D>, k: usize) -> Vec<(T, f64, [i32; D])> {
+        let mut nearest: Vec<(usize, f64, [i32; D])> = vec![];
+        if k == 0 {
+            return vec![];
+        }
+        if let Some(root) = self.root {
+            // A point missing from the k nearest of one image is preceded there by k
+            // other points, so merging the per-image answers loses nothing.
+            let (x, period) = (x.coordinates(), self.period());
+            for shift in self.image_shifts() {
+                let image = unshift(&x, &shift, &period);
+                if nearest.len() == k && nearest[k - 1].1 <= self.box_gap_square(&image) {
+                    continue;
+                }
+                let mut best = vec![];
+                self.search_k_nearest(root, &image, k, &mut best);
+                nearest.extend(best.into_iter().map(|(node, r2)| (node, r2, shift)));
+                nearest.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+                nearest.dedup_by_key(|a| a.0);
+                nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+                nearest.truncate(k);
+            }
+        }
+        nearest
+            .into_iter()
+            .map(|(node, r2, shift)| (self.nodes[node].item.clone(), r2.sqrt(), shift))
+            .collect()
+    }
+
+    // Adds a leaf under `node`, splitting on the axis after its parent's, and returns
+    // its index and depth below `node`.
+    #[allow(dead_code)]
+    fn insert_node(&mut self, node: usize, point: &[f64; D], id: usize, item: T) -> (usize, i32) {
+        let mut node = node;
+        let mut depth = 0;
+        loop {
+            let axis = self.nodes[node].axis;
+            depth += 1;
+            self.nodes[node].size += 1;
+            self.nodes[node].bucket = 0;
+
+            let goes_left = self.nodes[node].split > point[axis];
+            let next = if goes_left {
+                self.nodes[node].left
+            } else {
+                self.nodes[node].right
+            };
+            match next {
+                Some(child) => node = child,
+                None => {
+                    let child_axis = (axis + 1) % D;
+                    let split = point[child_axis];
+                    let child = self.push_node(point, child_axis, split, Some(node), id, item);
+                    if goes_left {
+                        self.nodes[node].left = Some(child);
+                    } else {
+                        self.nodes[node].right = Some(child);
+                    }
+                    return (child, depth);
+                }
+            }
+        }
+    }
+
+    // Adds `point` under a fresh id and returns the id.
+    #[allow(dead_code)]
+    fn insert_point(&mut self, point: &[f64; D], item: T) -> usize {
+        let id = self.slots.len();
+        match self.root {
+            Some(root) => {
+                self.insert_node(root, point, id, item);
+            }
+            None => {
+                self.root = Some(self.push_node(point, 0, point[0], None, id, item));
+            }
+        }
+        id
+    }
+
+    /// Adds `point` with payload `item` and returns its id.
+    ///
+    /// When the new node lands deeper than log(n) / log(1 / alpha), the lowest
+    /// ancestor with one child holding more than alpha of its points is rebuilt
+    /// balanced, so the tree stays O(log n) deep however points arrive.
+    #[allow(dead_code)]
+    pub fn insert_with_item(&mut self, point: &impl Coordinates<D>, item: T) -> usize {
+        let point = point.coordinates();
+        let id = self.slots.len();
+        match self.root {
+            Some(root) => {
+                let (node, depth) = self.insert_node(root, &point, id, item);
+                let max_depth = (self.size() as f64).ln() / (1.0 / SCAPEGOAT_ALPHA).ln();
+                if depth as f64 > max_depth {
+                    self.rebalance_above(node, depth);
+                }
+            }
+            None => {
+                self.root = Some(self.push_node(&point, 0, point[0], None, id, item));
+            }
+        }
+        id
+    }
+
+    // Finds the scapegoat on the path from `node`, at `depth`, to the root and
+    // rebuilds its subtree.
+    #[allow(dead_code)]
+    fn rebalance_above(&mut self, node: usize, mut depth: i32) {
+        let mut child = node;
+        while let Some(parent) = self.nodes[child].parent {
+            depth -= 1;
+            if self.nodes[child].size as f64 > SCAPEGOAT_ALPHA * self.nodes[parent].size as f64 {
+                self.rebuild_subtree(parent, depth);
+                return;
+            }
+            child = parent;
+        }
+        self.rebuild();
+    }
+
+    // Rebuilds the subtree under `node`, at `depth`, as a balanced tree at the end of
+    // `nodes`. The old nodes stay behind as tombstones until the next compaction.
+    #[allow(dead_code)]
+    fn rebuild_subtree(&mut self, node: usize, depth: i32) {
+        let parent = self.nodes[node].parent;
+        let mut points: Vec<Entry<D, T>> = Vec::with_capacity(self.nodes[node].size);
+        let mut stack = vec![node];
+        while let Some(index) = stack.pop() {
+            let current = &mut self.nodes[index];
+            if !current.removed {
+                current.removed = true;
+                self.removed += 1;
+                points.push((current.position, current.id, Some(current.item.clone())));
+            }
+            stack.extend(current.left);
+            stack.extend(current.right);
+        }
+
+        let cell = bounding_box(&points);
+        let subtree = self.build_balanced(&mut points, depth, parent, cell);
+        match parent {
+            Some(parent) if self.nodes[parent].left == Some(node) => {
+                self.nodes[parent].left = subtree;
+            }
+            Some(parent) => self.nodes[parent].right = subtree,
+            None => self.root = subtree,
+        }
+        if 2 * self.removed > self.nodes.len() {
+            self.compact();
+        }
+    }
+
+    #[allow(dead_code)]
+    fn create_kd_tree(&mut self, vec: &[[f64; D]], items: impl IntoIterator<Item = T>) {
+        for (point, item) in vec.iter().zip(items) {
+            self.insert_point(point, item);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn depth(&self) -> i32 {
+        match self.root {
+            Some(root) => self.subtree_depth(root),
+            None => 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn subtree_depth(&self, node: usize) -> i32 {
+        let mut max_depth = 0;
+        let mut stack = vec![(node, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+            stack.extend(self.nodes[node].left.map(|left| (left, depth + 1)));
+            stack.extend(self.nodes[node].right.map(|right| (right, depth + 1)));
+        }
+        max_depth
+    }
+
+    /// Builds the tree by inserting the points one by one, attaching `items[i]` to point `i`.
+    #[allow(dead_code)]
+    pub fn construct_kd_tree_with_items<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+    ) -> KDTree<D, T> {
+        let positions: Vec<[f64; D]> = vec.as_ref().iter().map(|p| p.coordinates()).collect();
+        assert_eq!(positions.len(), items.len());
+        let mut tree = KDTree::with_capacity(positions.len());
+        tree.create_kd_tree(&positions, items);
+        tree
+    }
+
+    /// Balanced counterpart of `construct_kd_tree_with_items`.
+    ///
+    /// Nodes are laid out in pre-order, so a left child directly follows its parent.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_with_items<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+    ) -> KDTree<D, T> {
+        Self::construct_balanced_kd_tree_with_items_and_options(vec, items, BuildOptions::default())
+    }
+
+    /// `construct_balanced_kd_tree_with_items` built, and later rebuilt, with `options`.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_with_items_and_options<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+        options: BuildOptions<D>,
+    ) -> KDTree<D, T> {
+        assert!(options.bucket_size >= 1);
+        let mut points = Self::entries(vec, items);
+        let mut tree = KDTree::with_capacity(points.len());
+        tree.options = options;
+        tree.slots.resize(points.len(), None);
+        let cell = bounding_box(&points);
+        tree.root = tree.build_balanced(&mut points, 0, None, cell);
+        tree
+    }
+
+    #[allow(dead_code)]
+    fn entries<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+    ) -> Vec<Entry<D, T>> {
+        let vec = vec.as_ref();
+        assert_eq!(vec.len(), items.len());
+        vec.iter()
+            .zip(items)
+            .enumerate()
+            .map(|(id, (p, item))| (p.coordinates(), id, Some(item)))
+            .collect()
+    }
+
+    // Picks the splitting plane for `points`, which lie in `cell`, and reorders them
+    // so that the node's point is at the returned index with the points of its left
+    // subtree before it. Returns (index, axis, split).
+    #[allow(dead_code)]
+    fn split_points(
+        policy: SplitPolicy,
+        points: &mut [Entry<D, T>],
+        depth: i32,
+        cell: &Cell<D>,
+    ) -> (usize, usize, f64) {
+        let axis = match policy {
+            SplitPolicy::RoundRobin => depth as usize % D,
+            SplitPolicy::MaxSpread => widest_axis(&bounding_box(points)),
+            SplitPolicy::SlidingMidpoint => widest_axis(cell),
+        };
+        if policy == SplitPolicy::SlidingMidpoint {
+            let split = 0.5 * (cell.0[axis] + cell.1[axis]);
+            let mut mid = 0;
+            for i in 0..points.len() {
+                if points[i].0[axis] < split {
+                    points.swap(i, mid);
+                    mid += 1;
+                }
+            }
+            // The node takes the first point at or above the midpoint. A split with an
+            // empty side would peel off one point per level, and never end on points
+            // tied along `axis`, so it falls back to the median instead.
+            if 0 < mid && mid + 1 < points.len() {
+                return (mid, axis, split);
+            }
+        }
+
+        let mid = points.len() / 2;
+        points.select_nth_unstable_by(mid, |a, b| a.0[axis].total_cmp(&b.0[axis]));
+        (mid, axis, points[mid].0[axis])
+    }
+
+    // `points` lie in `cell` and are reordered in place.
+    #[allow(dead_code)]
+    fn build_balanced(
+        &mut self,
+        points: &mut [Entry<D, T>],
+        depth: i32,
+        parent: Option<usize>,
+        cell: Cell<D>,
+    ) -> Option<usize> {
+        if points.is_empty() {
+            return None;
+        }
+        let (mid, axis, split) =
+            Self::split_points(self.options.split_policy, points, depth, &cell);
+
+        let (left_points, rest) = points.split_at_mut(mid);
+        let (median, right_points) = rest.split_first_mut().unwrap();
+        let item = median.2.take().unwrap();
+        let node = self.push_node(&median.0, axis, split, parent, median.1, item);
+        let (left_cell, right_cell) = split_cell(cell, axis, split);
+        self.nodes[node].left = self.build_balanced(left_points, depth + 1, Some(node), left_cell);
+        self.nodes[node].right =
+            self.build_balanced(right_points, depth + 1, Some(node), right_cell);
+        self.nodes[node].size = points.len();
+        if 1 < points.len() && points.len() <= self.options.bucket_size {
+            self.nodes[node].bucket = points.len();
+        }
+        Some(node)
+    }
+
+    #[allow(dead_code)]
+    pub fn size(&self) -> i32 {
+        (self.nodes.len() - self.removed) as i32
+    }
+
+    /// Removes the point with the given id, returning whether it was present.
+    /// The tree is compacted once more than half of its nodes are tombstones.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, id: usize) -> bool {
+        match self.slots.get(id).copied().flatten() {
+            Some(node) => {
+                self.tombstone(node);
+                self.slots[id] = None;
+                if 2 * self.removed > self.nodes.len() {
+                    self.compact();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Marks `node` as removed and drops it from the subtree sizes above it.
+    #[allow(dead_code)]
+    fn tombstone(&mut self, node: usize) {
+        self.nodes[node].removed = true;
+        self.removed += 1;
+        let mut current = Some(node);
+        while let Some(index) = current {
+            self.nodes[index].size -= 1;
+            current = self.nodes[index].parent;
+        }
+    }
+
+    /// Drops tombstoned nodes and rebuilds the remaining points as a balanced tree.
+    /// Ids are preserved.
+    #[allow(dead_code)]
+    pub fn compact(&mut self) {
+        if self.removed > 0 {
+            self.rebuild();
+        }
+    }
+
+    /// Rebuilds the live points as a balanced tree, keeping their ids.
+    #[allow(dead_code)]
+    pub fn rebuild(&mut self) {
+        let mut points: Vec<Entry<D, T>> = self
+            .nodes
+            .drain(..)
+            .filter(|node| !node.removed)
+            .map(|node| (node.position, node.id, Some(node.item)))
+            .collect();
+        self.slots.fill(None);
+        self.removed = 0;
+        let cell = bounding_box(&points);
+        self.root = self.build_balanced(&mut points, 0, None, cell);
+    }
+
+    /// Moves the point `id` to `point`, returning whether the id was present.
+    ///
+    /// The point stays in its node unless it crossed the splitting plane of one of
+    /// its ancestors, in which case it is re-inserted from the root. The tree is
+    /// rebuilt once it becomes too deep or holds too many stale nodes.
+    #[allow(dead_code)]
+    pub fn relocate(&mut self, id: usize, point: &impl Coordinates<D>) -> bool {
+        match self.move_point(id, &point.coordinates()) {
+            Some(depth) => {
+                if self.is_unbalanced(depth) {
+                    self.rebuild();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves every point `i` to `vec[i]`, as `relocate` does, checking the balance
+    /// of the tree once at the end.
+    #[allow(dead_code)]
+    pub fn update_positions<P: Coordinates<D>>(&mut self, vec: &(impl AsRef<[P]> + ?Sized)) {
+        let mut max_depth = 0;
+        for (id, p) in vec.as_ref().iter().enumerate() {
+            if let Some(depth) = self.move_point(id, &p.coordinates()) {
+                max_depth = max_depth.max(depth);
+            }
+        }
+        if self.is_unbalanced(max_depth) {
+            self.rebuild();
+        }
+    }
+
+    // Returns the depth of the node holding the point afterwards.
+    #[allow(dead_code)]
+    fn move_point(&mut self, id: usize, point: &[f64; D]) -> Option<i32> {
+        let node = self.slots.get(id).copied().flatten()?;
+        let root = self.root?;
+
+        let mut depth = 0;
+        let mut current = node;
+        while let Some(parent) = self.nodes[current].parent {
+            depth += 1;
+            current = parent;
+        }
+
+        let mut fits = true;
+        let mut child = node;
+        while let Some(parent) = self.nodes[child].parent {
+            let axis = self.nodes[parent].axis;
+            let split = self.nodes[parent].split;
+            fits &= if self.nodes[parent].left == Some(child) {
+                point[axis] <= split
+            } else {
+                point[axis] >= split
+            };
+            child = parent;
+        }
+
+        if fits {
+            self.nodes[node].position = *point;
+            return Some(depth);
+        }
+
+        let item = self.nodes[node].item.clone();
+        self.tombstone(node);
+        let (_, new_depth) = self.insert_node(root, point, id, item);
+        Some(new_depth)
+    }
+
+    #[allow(dead_code)]
+    fn is_unbalanced(&self, depth: i32) -> bool {
+        let max_depth = MAX_DEPTH_FACTOR * (self.nodes.len() as f64).log2().max(1.0);
+        depth as f64 > max_depth || 2 * self.removed > self.nodes.len()
+    }
+}
+
+impl<const D: usize> KDTree<D> {
+    /// Adds `point` and returns its id, which is also its payload.
+    /// See `insert_with_item`.
+    #[allow(dead_code)]
+    pub fn insert(&mut self, point: &impl Coordinates<D>) -> usize {
+        let id = self.slots.len();
+        self.insert_with_item(point, id)
+    }
+
+    #[allow(dead_code)]
+    pub fn construct_kd_tree<P: Coordinates<D>>(vec: &(impl AsRef<[P]> + ?Sized)) -> KDTree<D> {
+        let ids = (0..vec.as_ref().len()).collect();
+        Self::construct_kd_tree_with_items(vec, ids)
+    }
+
+    /// Builds a balanced tree by splitting on the median along the alternating axis.
+    /// Unlike `construct_kd_tree`, the depth is O(log n) regardless of input order.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+    ) -> KDTree<D> {
+        let ids = (0..vec.as_ref().len()).collect();
+        Self::construct_balanced_kd_tree_with_items(vec, ids)
+    }
+
+    /// `construct_balanced_kd_tree` built, and later rebuilt, with `options`.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_with_options<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        options: BuildOptions<D>,
+    ) -> KDTree<D> {
+        let ids = (0..vec.as_ref().len()).collect();
+        Self::construct_balanced_kd_tree_with_items_and_options(vec, ids, options)
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<const D: usize> KDTree<D> {
+    /// Multithreaded `construct_balanced_kd_tree`; the result is identical.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_parallel<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+    ) -> KDTree<D> {
+        let ids = (0..vec.as_ref().len()).collect();
+        Self::construct_balanced_kd_tree_parallel_with_items(vec, ids)
+    }
+
+    /// Multithreaded `construct_balanced_kd_tree_with_options`; the result is identical.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_parallel_with_options<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        options: BuildOptions<D>,
+    ) -> KDTree<D> {
+        let ids = (0..vec.as_ref().len()).collect();
+        Self::construct_balanced_kd_tree_parallel_with_items_and_options(vec, ids, options)
+    }
+}
+
+// Subtrees smaller than this are built on the current thread.
+#[cfg(feature = "parallel")]
+const PARALLEL_BUILD_CUTOFF: usize = 1 << 12;
+
+#[cfg(feature = "parallel")]
+impl<const D: usize, T: Clone + Send + Sync> KDTree<D, T> {
+    /// Multithreaded `construct_balanced_kd_tree_with_items`.
+    ///
+    /// The two halves left after each median partition are built concurrently.
+    /// Both subtrees have a known size, so each writes straight into its own part
+    /// of the pre-order node array and the tree matches the sequential build exactly.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_parallel_with_items<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+    ) -> KDTree<D, T> {
+        let options = BuildOptions::default();
+        Self::construct_balanced_kd_tree_parallel_with_items_and_options(vec, items, options)
+    }
+
+    /// Multithreaded `construct_balanced_kd_tree_with_items_and_options`.
+    #[allow(dead_code)]
+    pub fn construct_balanced_kd_tree_parallel_with_items_and_options<P: Coordinates<D>>(
+        vec: &(impl AsRef<[P]> + ?Sized),
+        items: Vec<T>,
+        options: BuildOptions<D>,
+    ) -> KDTree<D, T> {
+        assert!(options.bucket_size >= 1);
+        let mut points = Self::entries(vec, items);
+        let mut nodes: Vec<Option<Node<D, T>>> = (0..points.len()).map(|_| None).collect();
+        let cell = bounding_box(&points);
+        Self::build_balanced_parallel(&options, &mut points, &mut nodes, 0, 0, None, cell);
+
+        let mut tree = KDTree::with_capacity(points.len());
+        tree.options = options;
+        tree.nodes.extend(nodes.into_iter().map(Option::unwrap));
+        tree.slots.resize(points.len(), None);
+        for (i, node) in tree.nodes.iter().enumerate() {
+            tree.slots[node.id] = Some(i);
+        }
+        tree.root = if tree.nodes.is_empty() { None } else { Some(0) };
+        tree
+    }
+
+    // Same recursion as `build_balanced`, writing the subtree into `out`, whose first
+    // element is node `offset`.
+    #[allow(dead_code)]
+    fn build_balanced_parallel(
+        options: &BuildOptions<D>,
+        points: &mut [Entry<D, T>],
+        out: &mut [Option<Node<D, T>>],
+        offset: usize,
+        depth: i32,
+        parent: Option<usize>,
+        cell: Cell<D>,
+    ) {
+        if points.is_empty() {
+            return;
+        }
+        let size = points.len();
+        let (mid, axis, split) = Self::split_points(options.split_policy, points, depth, &cell);
+
+        let (left_points, rest) = points.split_at_mut(mid);
+        let (median, right_points) = rest.split_first_mut().unwrap();
+        let item = median.2.take().unwrap();
+        let mut node = Node::new(&median.0, axis, split, parent, median.1, item);
+        let left_offset = offset + 1;
+        let right_offset = offset + 1 + left_points.len();
+        node.left = (!left_points.is_empty()).then_some(left_offset);
+        node.right = (!right_points.is_empty()).then_some(right_offset);
+        node.size = size;
+        if 1 < size && size <= options.bucket_size {
+            node.bucket = size;
+        }
+
+        let (slot, rest_out) = out.split_first_mut().unwrap();
+        *slot = Some(node);
+        let (left_out, right_out) = rest_out.split_at_mut(left_points.len());
+        let (child_depth, parent) = (depth + 1, Some(offset));
+        let (left_cell, right_cell) = split_cell(cell, axis, split);
+        if size > PARALLEL_BUILD_CUTOFF {
+            rayon::join(
+                || {
+                    Self::build_balanced_parallel(
+                        options,
+                        left_points,
+                        left_out,
+                        left_offset,
+                        child_depth,
+                        parent,
+                        left_cell,
+                    )
+                },
+                || {
+                    Self::build_balanced_parallel(
+                        options,
+                        right_points,
+                        right_out,
+                        right_offset,
+                        child_depth,
+                        parent,
+                        right_cell,
+                    )
+                },
+            );
+        } else {
+            Self::build_balanced_parallel(
+                options,
+                left_points,
+                left_out,
+                left_offset,
+                child_depth,
+                parent,
+                left_cell,
+            );
+            Self::build_balanced_parallel(
+                options,
+                right_points,
+                right_out,
+                right_offset,
+                child_depth,
+                parent,
+                right_cell,
+            );
+        }
+    }
+
+    /// Runs `neighbor_search` for every point of `xs` on the rayon thread pool.
+    /// The result for `xs[i]` is at index `i`.
+    #[allow(dead_code)]
+    pub fn batch_neighbor_search<P: Coordinates<D> + Sync>(
+        &self,
+        xs: &[P],
+        radius: f64,
+    ) -> Vec<Vec<T>> {
+        use rayon::prelude::*;
+        xs.par_iter()
+            .map(|x| self.neighbor_search(x, radius))
+            .collect()
+    }
+
+    /// Runs `k_nearest` for every point of `xs` on the rayon thread pool.
+    /// The result for `xs[i]` is at index `i`.
+    #[allow(dead_code)]
+    pub fn batch_k_nearest<P: Coordinates<D> + Sync>(
+        &self,
+        xs: &[P],
+        k: usize,
+    ) -> Vec<Vec<(T, f64)>> {
+        use rayon::prelude::*;
+        xs.par_iter().map(|x| self.k_nearest(x, k)).collect()
+    }
+}
+
+// The original tests predate these lints.
+#[cfg(test)]
+#[allow(
+    clippy::unnecessary_cast,
+    clippy::unnecessary_mut_passed,
+    clippy::duration_subsec,
+    clippy::assign_op_pattern
+)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    // Counts the heap allocations made by each thread, so that tests can check that a
+    // query does not allocate.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    #[test]
+    fn depth_and_size() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 600;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&mut vec);
+
+        assert_eq!(tree.depth(), 20);
+        assert_eq!(tree.size(), 600);
+    }
+
+    #[test]
+    fn balanced_depth_and_size() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 600;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+
+        assert_eq!(tree.depth(), 9);
+        assert_eq!(tree.size(), 600);
+
+        let center = Grid2D { x: 0.4, y: 0.3 };
+        let radius = 0.5;
+        let mut near = tree.neighbor_search(&center, radius);
+        near.sort();
+        let mut expected: Vec<usize> = (0..num_point)
+            .filter(|&i| vec.points[i].distance_square(&center).sqrt() < radius)
+            .collect();
+        expected.sort();
+        assert_eq!(near, expected);
+        assert_eq!(
+            tree.k_nearest(&center, 5),
+            KDTree::construct_kd_tree(&vec).k_nearest(&center, 5)
+        );
+    }
+
+    #[test]
+    fn balanced_preorder_layout() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 100;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+
+        assert_eq!(tree.nodes.len(), num_point);
+        assert_eq!(tree.root, Some(0));
+        for (i, node) in tree.nodes.iter().enumerate() {
+            if let Some(left_node) = node.left {
+                assert_eq!(left_node, i + 1);
+            }
+            if let Some(right_node) = node.right {
+                assert!(right_node > i);
+            }
+        }
+    }
+
+    #[test]
+    fn balanced_sorted_input() {
+        let num_point: usize = 1 << 12;
+        let mut vec = Points2D::new();
+        for i in 0..num_point {
+            vec.push(i as f64, i as f64);
+        }
+
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+
+        assert_eq!(tree.depth(), 12);
+        assert_eq!(tree.size(), num_point as i32);
+        assert_eq!(tree.nearest(&Grid2D::new(100.2, 100.2)).unwrap().0, 100);
+    }
+
+    #[test]
+    fn empty_tree() {
+        let vec = Points2D::new();
+        let center = Grid2D::new(0.0, 0.0);
+
+        for mut tree in [
+            KDTree::construct_kd_tree(&vec),
+            KDTree::construct_balanced_kd_tree(&vec),
+            KDTree::new(),
+        ] {
+            assert_eq!(tree.size(), 0);
+            assert_eq!(tree.depth(), 0);
+            assert_eq!(tree.number_of_leaves(), 0);
+            assert!(tree.neighbor_search(&center, 1.0).is_empty());
+            assert!(tree.k_nearest(&center, 3).is_empty());
+            assert_eq!(tree.nearest(&center), None);
+            assert!(!tree.remove(0));
+            assert!(!tree.relocate(0, &center));
+            tree.rebuild();
+
+            assert_eq!(tree.insert_point(&[0.5, 0.5], 0), 0);
+            assert_eq!(tree.insert_point(&[-0.5, 0.5], 1), 1);
+            assert_eq!(tree.size(), 2);
+            assert_eq!(tree.nearest(&center).unwrap().0, 0);
+            assert_eq!(tree.neighbor_search(&Grid2D::new(-0.5, 0.4), 0.2), [1]);
+
+            assert!(tree.remove(0));
+            assert!(tree.remove(1));
+            assert_eq!(tree.size(), 0);
+            assert_eq!(tree.nearest(&center), None);
+            assert!(tree.neighbor_search(&center, 10.0).is_empty());
+
+            assert_eq!(tree.insert_point(&[0.1, 0.1], 2), 2);
+            assert_eq!(tree.size(), 1);
+            assert_eq!(tree.nearest(&center).unwrap().0, 2);
+        }
+    }
+
+    #[test]
+    fn search_1() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 10;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&mut vec);
+        let center = Grid2D { x: 0.0, y: 0.0 };
+        let radius = 0.4;
+        let near = tree.neighbor_search(&center, radius);
+
+        assert_eq!(near, [1 as usize, 9 as usize].to_vec());
+    }
+
+    #[test]
+    fn search_2() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 10;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&mut vec);
+        let center = Grid2D { x: 0.4, y: 0.3 };
+        let radius = 0.5;
+        let near = tree.neighbor_search(&center, radius);
+
+        assert_eq!(
+            near,
+            [1 as usize, 2 as usize, 6 as usize, 9 as usize, 5 as usize].to_vec()
+        );
+    }
+
+    #[test]
+    fn search_with_distances() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 10;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&vec);
+        let balanced = KDTree::construct_balanced_kd_tree(&vec);
+        let center = Grid2D { x: 0.4, y: 0.3 };
+        let radius = 0.5;
+        let ids = |near: Vec<(usize, f64)>| -> Vec<usize> {
+            near.into_iter().map(|(id, _)| id).collect()
+        };
+
+        let near = tree.neighbor_search_with_distances(&center, radius, NeighborOrder::Traversal);
+        assert_eq!(ids(near.clone()), [1, 2, 6, 9, 5]);
+        for (id, r2) in near {
+            assert_eq!(r2, vec.points[id].distance_square(&center));
+        }
+
+        let by_id = tree.neighbor_search_with_distances(&center, radius, NeighborOrder::Id);
+        assert_eq!(ids(by_id.clone()), [1, 2, 5, 6, 9]);
+        assert_eq!(
+            balanced.neighbor_search_with_distances(&center, radius, NeighborOrder::Id),
+            by_id
+        );
+
+        let by_distance =
+            tree.neighbor_search_with_distances(&center, radius, NeighborOrder::Distance);
+        assert!(by_distance.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(
+            balanced.neighbor_search_with_distances(&center, radius, NeighborOrder::Distance),
+            by_distance
+        );
+    }
+
+    #[test]
+    fn for_each_within_visits_neighbors() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&vec);
+        let center = Grid2D::new(0.2, -0.1);
+        let radius = 0.3;
+
+        let mut visited = vec![];
+        let flow = tree.for_each_within(&center, radius, |&id, position, r2| {
+            assert_eq!(*position, vec.points[id].coordinates());
+            assert_eq!(r2, vec.points[id].distance_square(&center));
+            visited.push(id);
+            ControlFlow::<()>::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(visited, tree.neighbor_search(&center, radius));
+
+        let mut count = 0;
+        let flow = tree.for_each_within(&center, radius, |&id, _, _| {
+            count += 1;
+            if count == 3 {
+                ControlFlow::Break(id)
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(count, 3);
+        assert_eq!(flow, ControlFlow::Break(visited[2]));
+
+        // A force-style accumulation over every point's neighbors stays off the heap.
+        let before = allocations();
+        let mut total = 0.0;
+        for point in &vec.points {
+            let _ = tree.for_each_within(point, radius, |_, _, r2| {
+                total += r2;
+                ControlFlow::<()>::Continue(())
+            });
+        }
+        assert_eq!(allocations(), before);
+        assert!(total > 0.0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_build_matches_sequential() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+
+        for num_point in [0, 1, 2, 3, 100, 100_000] {
+            let mut vec = Points2D::new();
+            for _ in 0..num_point {
+                let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+                let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+                vec.push(x_r, y_r);
+            }
+
+            let sequential = KDTree::construct_balanced_kd_tree(&vec);
+            let parallel = KDTree::construct_balanced_kd_tree_parallel(&vec);
+            assert_eq!(parallel.root, sequential.root);
+            assert_eq!(parallel.slots, sequential.slots);
+            assert!(parallel.nodes == sequential.nodes);
+
+            for split_policy in [SplitPolicy::MaxSpread, SplitPolicy::SlidingMidpoint] {
+                let options = BuildOptions {
+                    split_policy,
+                    bucket_size: 8,
+                    ..Default::default()
+                };
+                let sequential = KDTree::construct_balanced_kd_tree_with_options(&vec, options);
+                let parallel =
+                    KDTree::construct_balanced_kd_tree_parallel_with_options(&vec, options);
+                assert_eq!(parallel.options, options);
+                assert_eq!(parallel.slots, sequential.slots);
+                assert!(parallel.nodes == sequential.nodes);
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn batch_queries() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 2000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+        let radius = 0.1;
+
+        let near = tree.batch_neighbor_search(&vec.points, radius);
+        assert_eq!(near.len(), num_point);
+        for (i, point) in vec.points.iter().enumerate() {
+            assert_eq!(near[i], tree.neighbor_search(point, radius));
+        }
+
+        let nearest = tree.batch_k_nearest(&vec.points, 4);
+        for (i, point) in vec.points.iter().enumerate() {
+            assert_eq!(nearest[i], tree.k_nearest(point, 4));
+            assert_eq!(nearest[i][0], (i, 0.0));
+        }
+    }
+
+    #[test]
+    fn pairs_within_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 500;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let mut live = vec![true; num_point];
+        let mut incremental = KDTree::construct_kd_tree(&vec);
+        for id in (0..num_point).step_by(11) {
+            incremental.remove(id);
+            live[id] = false;
+        }
+
+        for radius in [0.0, 0.05, 0.2] {
+            let mut expected = vec![];
+            for i in 0..num_point {
+                for j in i + 1..num_point {
+                    if vec.points[i].distance_square(&vec.points[j]).sqrt() < radius {
+                        expected.push((i, j));
+                    }
+                }
+            }
+            let balanced = KDTree::construct_balanced_kd_tree(&vec);
+            assert_eq!(balanced.pairs_within(radius), expected);
+
+            expected.retain(|&(i, j)| live[i] && live[j]);
+            assert_eq!(incremental.pairs_within(radius), expected);
+        }
+        assert!(KDTree::<2>::new().pairs_within(1.0).is_empty());
+    }
+
+    #[test]
+    fn count_within_matches_search() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 2000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let mut tree = KDTree::construct_balanced_kd_tree(&vec);
+        for id in (0..num_point).step_by(7) {
+            tree.remove(id);
+        }
+        for id in (1..num_point).step_by(5) {
+            let p = &vec.points[id];
+            tree.relocate(id, &Grid2D::new(-p.y, p.x));
+        }
+        assert_eq!(tree.nodes[tree.root.unwrap()].size, tree.size() as usize);
+
+        for radius in [0.0, 0.05, 0.3, 1.0, 4.0] {
+            for _ in 0..20 {
+                let center = Grid2D::new(
+                    2.0 * (rng.gen::<f64>() - 0.5),
+                    2.0 * (rng.gen::<f64>() - 0.5),
+                );
+                assert_eq!(
+                    tree.count_within(&center, radius),
+                    tree.neighbor_search(&center, radius).len()
+                );
+            }
+        }
+
+        let center = Grid2D::new(0.1, -0.2);
+        let before = allocations();
+        let count = tree.count_within(&center, 0.3);
+        assert_eq!(allocations(), before);
+        assert_eq!(count, tree.neighbor_search(&center, 0.3).len());
+        assert_eq!(
+            KDTree::<2>::new().count_within(&Grid2D::new(0.0, 0.0), 1.0),
+            0
+        );
+    }
+
+    #[test]
+    fn range_query_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let min = Grid2D::new(-0.3, 0.1);
+        let max = Grid2D::new(0.5, 0.4);
+        let expected: Vec<usize> = (0..num_point)
+            .filter(|&i| {
+                let p = &vec.points[i];
+                min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y
+            })
+            .collect();
+        assert!(!expected.is_empty());
+
+        for tree in [
+            KDTree::construct_kd_tree(&vec),
+            KDTree::construct_balanced_kd_tree(&vec),
+        ] {
+            let mut found = tree.range_query(&min, &max);
+            found.sort();
+            assert_eq!(found, expected);
+            assert!(tree.range_query(&max, &min).is_empty());
+        }
+
+        let tree = KDTree::construct_kd_tree(&vec);
+        let corner = &vec.points[expected[0]];
+        assert_eq!(tree.range_query(corner, corner), [expected[0]]);
+    }
+
+    #[test]
+    fn k_nearest_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&vec);
+        let center = Grid2D::new(0.1, -0.2);
+        let k = 7;
+        let near = tree.k_nearest(&center, k);
+
+        let mut expected: Vec<(usize, f64)> = (0..num_point)
+            .map(|i| (i, vec.points[i].distance_square(&center).sqrt()))
+            .collect();
+        expected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        expected.truncate(k);
+
+        assert_eq!(near, expected);
+        assert!(tree.k_nearest(&center, 0).is_empty());
+        assert_eq!(tree.k_nearest(&center, 2 * num_point).len(), num_point);
+    }
+
+    #[test]
+    fn nearest_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&vec);
+
+        for _ in 0..100 {
+            let center = Grid2D::new(
+                3.0 * (rng.gen::<f64>() - 0.5),
+                3.0 * (rng.gen::<f64>() - 0.5),
+            );
+            let expected = (0..num_point)
+                .map(|i| (i, vec.points[i].distance_square(&center).sqrt()))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+            assert_eq!(tree.nearest(&center), expected);
+        }
+    }
+
+    #[test]
+    fn count_leaves() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 10;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let tree = KDTree::construct_kd_tree(&mut vec);
+
+        let center = Grid2D { x: 0.4, y: 0.3 };
+        let radius = 0.5;
+        let mut near = vec![0 as usize; 0];
+        tree.search_points_id(tree.root.unwrap(), &center.coordinates(), radius, &mut near);
+
+        assert_eq!(tree.number_of_leaves(), 4);
+    }
+
+    #[test]
+    fn remove_points() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let mut tree = KDTree::construct_kd_tree(&vec);
+        let center = Grid2D::new(0.1, 0.2);
+        let radius = 0.3;
+
+        let mut live = vec![true; num_point];
+        for id in (0..num_point).step_by(3) {
+            assert!(tree.remove(id));
+            assert!(!tree.remove(id));
+            live[id] = false;
+        }
+        assert_eq!(tree.size(), (num_point - num_point.div_ceil(3)) as i32);
+        assert_eq!(tree.nodes.len(), num_point);
+
+        let brute_force = |live: &[bool]| {
+            let near: Vec<usize> = (0..num_point)
+                .filter(|&i| live[i] && vec.points[i].distance_square(&center).sqrt() < radius)
+                .collect();
+            let nearest = (0..num_point)
+                .filter(|&i| live[i])
+                .map(|i| (i, vec.points[i].distance_square(&center).sqrt()))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            (near, nearest)
+        };
+
+        let (expected, expected_nearest) = brute_force(&live);
+        let mut near = tree.neighbor_search(&center, radius);
+        near.sort();
+        assert_eq!(near, expected);
+        assert_eq!(tree.nearest(&center), expected_nearest);
+        assert_eq!(tree.k_nearest(&center, 1), vec![expected_nearest.unwrap()]);
+
+        for id in (1..num_point).step_by(3) {
+            assert!(tree.remove(id));
+            live[id] = false;
+        }
+        assert!(tree.nodes.len() < num_point);
+        assert_eq!(tree.size(), live.iter().filter(|&&l| l).count() as i32);
+
+        let (expected, expected_nearest) = brute_force(&live);
+        let mut near = tree.neighbor_search(&center, radius);
+        near.sort();
+        assert_eq!(near, expected);
+        assert_eq!(tree.nearest(&center), expected_nearest);
+        assert!(!tree.remove(1));
+        assert!(tree.remove(2));
+    }
+
+    #[test]
+    fn relocate_points() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+
+        let mut tree = KDTree::construct_balanced_kd_tree(&vec);
+        let center = Grid2D::new(-0.2, 0.1);
+        let radius = 0.3;
+
+        for step in 0..20 {
+            for point in vec.points.iter_mut() {
+                point.x += 0.01 * (rng.gen::<f64>() - 0.5);
+                point.y += 0.01 * (rng.gen::<f64>() - 0.5);
+            }
+            tree.update_positions(&vec);
+
+            assert_eq!(tree.size(), num_point as i32);
+            if step == 0 {
+                assert!(tree.removed < num_point / 10);
+            }
+
+            let mut near = tree.neighbor_search(&center, radius);
+            near.sort();
+            let expected: Vec<usize> = (0..num_point)
+                .filter(|&i| vec.points[i].distance_square(&center).sqrt() < radius)
+                .collect();
+            assert_eq!(near, expected);
+
+            let expected_nearest = (0..num_point)
+                .map(|i| (i, vec.points[i].distance_square(&center).sqrt()))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            assert_eq!(tree.nearest(&center), expected_nearest);
+        }
+
+        assert!(tree.relocate(7, &Grid2D::new(5.0, 5.0)));
+        assert_eq!(tree.nearest(&Grid2D::new(4.0, 4.0)).unwrap().0, 7);
+        assert!(!tree.relocate(num_point, &Grid2D::new(0.0, 0.0)));
+        assert_eq!(tree.size(), num_point as i32);
+    }
+
+    #[test]
+    fn metric_search_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
         }
-    }
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
 
-    #[allow(dead_code)]
-    fn number_of_leaves(&self) -> usize {
-        match (&self.left, &self.right) {
-            (None, None) => 1,
-            (Some(left_node), None) => left_node.number_of_leaves(),
-            (None, Some(right_node)) => right_node.number_of_leaves(),
-            (Some(left_node), Some(right_node)) => {
-                left_node.number_of_leaves() + right_node.number_of_leaves()
-            }
+        fn check(tree: &KDTree, vec: &Points2D, x: &[f64; 2], metric: &impl Metric<2>) {
+            let radius = 0.2;
+            let distances: Vec<f64> = vec
+                .points
+                .iter()
+                .map(|p| metric.distance(&p.coordinates(), x))
+                .collect();
+
+            let mut near = tree.neighbor_search_with_metric(x, radius, metric);
+            near.sort();
+            let expected: Vec<usize> = (0..distances.len())
+                .filter(|&i| distances[i] < radius)
+                .collect();
+            assert_eq!(near, expected);
+
+            let k = 7;
+            let mut expected: Vec<(usize, f64)> = distances.iter().copied().enumerate().collect();
+            expected.sort_by(|a, b| a.1.total_cmp(&b.1));
+            expected.truncate(k);
+            assert_eq!(tree.k_nearest_with_metric(x, k, metric), expected);
         }
-    }
 
-    #[allow(dead_code)]
-    fn is_leaf(&self) -> bool {
-        match (&self.left, &self.right) {
-            (None, None) => true,
-            (Some(_), None) => false,
-            (None, Some(_)) => false,
-            (Some(_), Some(_)) => false,
+        for _ in 0..100 {
+            let x = [
+                2.0 * (rng.gen::<f64>() - 0.5),
+                2.0 * (rng.gen::<f64>() - 0.5),
+            ];
+            check(&tree, &vec, &x, &Euclidean);
+            check(&tree, &vec, &x, &Manhattan);
+            check(&tree, &vec, &x, &Chebyshev);
+            check(&tree, &vec, &x, &WeightedEuclidean::new([4.0, 0.25]));
         }
-    }
 
-    #[allow(dead_code)]
-    pub fn neighbor_search(&self, x: &Grid2D, radius: f64) -> Vec<usize> {
-        let mut near = vec![0; 0];
-        self.search_points_id(x, radius, &mut near, 0);
-        near.clone()
+        let center = Grid2D::new(0.1, -0.2);
+        let mut near = tree.neighbor_search_with_metric(&center, 0.3, &Euclidean);
+        let mut expected = tree.neighbor_search(&center, 0.3);
+        near.sort();
+        expected.sort();
+        assert_eq!(near, expected);
     }
 
-    #[allow(dead_code)]
-    fn search_points_id(&self, x: &Grid2D, radius: f64, near: &mut Vec<usize>, mut depth: i32) {
-        let axis = depth % 2;
-        let r_self = self.position.distance_square(x).sqrt();
-        if r_self < radius {
-            near.push(self.id);
+    #[test]
+    fn periodic_queries() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 1000;
+
+        let mut vec = Points2D::new();
+        for _ in 0..num_point {
+            vec.push(rng.gen::<f64>(), 2.0 * rng.gen::<f64>());
         }
 
-        match axis {
-            0 => {
-                if self.position.x < x.x - radius {
-                    match &self.right {
-                        Some(right_node) => {
-                            depth += 1;
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        None => {}
+        let boxes = [
+            ([0.0, 0.0], [1.0, 2.0]),
+            ([0.0, f64::NEG_INFINITY], [1.0, f64::INFINITY]),
+        ];
+        for periodic_box in boxes {
+            let period = [1.0, periodic_box.1[1] - periodic_box.0[1]];
+            let options = BuildOptions {
+                periodic_box,
+                ..Default::default()
+            };
+            let mut tree = KDTree::construct_balanced_kd_tree_with_options(&vec, options);
+            tree.rebuild();
+            let image = |p: &Grid2D, shift: [i32; 2]| {
+                let mut image = [p.x, p.y];
+                for axis in 0..2 {
+                    if shift[axis] != 0 {
+                        image[axis] += shift[axis] as f64 * period[axis];
                     }
-                } else if x.x + radius < self.position.x {
-                    match &self.left {
-                        Some(left_node) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                        }
-                        None => {}
-                    }
-                } else {
-                    match (&self.right, &self.left) {
-                        (Some(left_node), Some(right_node)) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        (Some(left_node), None) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                        }
-                        (None, Some(right_node)) => {
-                            depth += 1;
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        (None, None) => {}
+                }
+                image
+            };
+            let min_image_distance = |p: &Grid2D, x: &[f64; 2]| {
+                let mut r2 = 0.0;
+                for axis in 0..2 {
+                    let mut d = (p.coordinates()[axis] - x[axis]).abs();
+                    if period[axis].is_finite() {
+                        d = d.min(period[axis] - d);
                     }
+                    r2 += d * d;
+                }
+                r2.sqrt()
+            };
+
+            for _ in 0..100 {
+                let x = [rng.gen::<f64>(), 2.0 * rng.gen::<f64>()];
+                let radius = 0.3;
+
+                let mut near = tree.periodic_neighbor_search(&x, radius);
+                near.sort_by_key(|a| a.0);
+                let expected: Vec<usize> = (0..num_point)
+                    .filter(|&i| min_image_distance(&vec.points[i], &x) < radius)
+                    .collect();
+                assert_eq!(near.iter().map(|a| a.0).collect::<Vec<_>>(), expected);
+                let mut plain = tree.neighbor_search(&x, radius);
+                plain.sort();
+                assert_eq!(plain, expected);
+                assert_eq!(tree.count_within(&x, radius), expected.len());
+                let mut visited = 0;
+                let _ = tree.for_each_within(&x, radius, |&i, _, r2| {
+                    assert!((r2.sqrt() - min_image_distance(&vec.points[i], &x)).abs() < 1e-12);
+                    visited += 1;
+                    ControlFlow::<()>::Continue(())
+                });
+                assert_eq!(visited, expected.len());
+                for (i, shift) in near {
+                    let r = distance_square(&image(&vec.points[i], shift), &x).sqrt();
+                    assert!((r - min_image_distance(&vec.points[i], &x)).abs() < 1e-12);
+                }
+
+                let k = 10;
+                let nearest = tree.periodic_k_nearest(&x, k);
+                let mut expected: Vec<f64> = vec
+                    .points
+                    .iter()
+                    .map(|p| min_image_distance(p, &x))
+                    .collect();
+                expected.sort_by(|a, b| a.total_cmp(b));
+                assert_eq!(nearest.len(), k);
+                for (j, &(i, r, shift)) in nearest.iter().enumerate() {
+                    assert!((r - expected[j]).abs() < 1e-12);
+                    let r_image = distance_square(&image(&vec.points[i], shift), &x).sqrt();
+                    assert!((r - r_image).abs() < 1e-12);
                 }
+                let plain = tree.k_nearest(&x, k);
+                assert!((0..k).all(|j| (plain[j].1 - expected[j]).abs() < 1e-12));
+                assert!((tree.nearest(&x).unwrap().1 - expected[0]).abs() < 1e-12);
             }
-            _ => {
-                if self.position.y < x.y - radius {
-                    match &self.right {
-                        Some(right_node) => {
-                            depth += 1;
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        None => {}
-                    }
-                } else if x.y + radius < self.position.y {
-                    match &self.left {
-                        Some(left_node) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                        }
-                        None => {}
-                    }
-                } else {
-                    match (&self.right, &self.left) {
-                        (Some(left_node), Some(right_node)) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        (Some(left_node), None) => {
-                            depth += 1;
-                            left_node.search_points_id(x, radius, near, depth);
-                        }
-                        (None, Some(right_node)) => {
-                            depth += 1;
-                            right_node.search_points_id(x, radius, near, depth);
-                        }
-                        (None, None) => {}
+            assert_eq!(
+                tree.periodic_k_nearest(&[0.5, 1.0], 2 * num_point).len(),
+                num_point
+            );
+
+            // Far from every side of the box, only the zero shift is searched.
+            let interior = tree.periodic_neighbor_search(&[0.5, 1.0], 0.3);
+            assert!(interior.iter().all(|&(_, shift)| shift == [0, 0]));
+
+            let radius = 0.05;
+            let mut expected = vec![];
+            for i in 0..num_point {
+                for j in i + 1..num_point {
+                    let x = vec.points[j].coordinates();
+                    if min_image_distance(&vec.points[i], &x) < radius {
+                        expected.push((i, j));
                     }
                 }
             }
+            assert_eq!(tree.pairs_within(radius), expected);
         }
+
+        // A tree built by insertion can be given the box afterwards.
+        let corner = [0.0, 0.0];
+        let tree = KDTree::construct_kd_tree(&vec).with_periodic_box(&boxes[0].0, &boxes[0].1);
+        let mut near = tree.neighbor_search(&corner, 0.1);
+        near.sort();
+        let options = BuildOptions {
+            periodic_box: boxes[0],
+            ..Default::default()
+        };
+        let built = KDTree::construct_balanced_kd_tree_with_options(&vec, options);
+        let mut expected = built.neighbor_search(&corner, 0.1);
+        expected.sort();
+        assert!(!expected.is_empty());
+        assert_eq!(near, expected);
     }
 
-    #[allow(dead_code)]
-    fn insert(&mut self, point: &Grid2D, mut depth: i32, id: usize) {
-        let axis = depth % 2;
+    #[test]
+    fn insert_rebalances() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 100_000;
 
-        match axis {
-            0 => {
-                if self.position.x > point.x {
-                    match &mut self.left {
-                        Some(left_node) => {
-                            depth += 1;
-                            left_node.insert(point, depth, id);
-                        }
-                        None => {
-                            let node = Self::new(point, id);
-                            self.left = Some(Box::new(node));
-                        }
-                    }
-                } else {
-                    match &mut self.right {
-                        Some(right_node) => {
-                            depth += 1;
-                            right_node.insert(point, depth, id);
-                        }
-                        None => {
-                            let node = Self::new(point, id);
-                            self.right = Some(Box::new(node));
-                        }
-                    }
-                }
+        let mut tree = KDTree::new();
+        let mut vec = Points2D::new();
+        for i in 0..num_point {
+            let x_r = i as f64 / num_point as f64;
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+            assert_eq!(tree.insert(&vec.points[i]), i);
+        }
+        let max_depth = (num_point as f64).ln() / (1.0 / SCAPEGOAT_ALPHA).ln();
+        assert!(tree.depth() as f64 <= max_depth + 1.0);
+        assert_eq!(tree.size() as usize, num_point);
+
+        for id in (0..num_point).step_by(3) {
+            assert!(tree.remove(id));
+        }
+        for i in num_point..2 * num_point {
+            vec.push(rng.gen::<f64>(), rng.gen::<f64>());
+            assert_eq!(tree.insert(&vec.points[i]), i);
+        }
+        assert!(tree.depth() as f64 <= 2.0 * max_depth);
+
+        let center = Grid2D::new(0.5, 0.3);
+        let radius = 0.05;
+        let mut near = tree.neighbor_search(&center, radius);
+        near.sort();
+        let expected: Vec<usize> = (0..2 * num_point)
+            .filter(|&i| i >= num_point || i % 3 != 0)
+            .filter(|&i| vec.points[i].distance_square(&center).sqrt() < radius)
+            .collect();
+        assert_eq!(near, expected);
+    }
+
+    #[test]
+    fn monotonic_input() {
+        let num_point: usize = 20_000;
+        let mut vec = Points2D::new();
+        for i in 0..num_point {
+            vec.push(i as f64, i as f64);
+        }
+
+        // A stack far smaller than the tree is deep.
+        let handle = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let tree = KDTree::construct_kd_tree(&vec);
+                assert_eq!(tree.depth(), num_point as i32 - 1);
+                assert_eq!(tree.size(), num_point as i32);
+                assert_eq!(tree.number_of_leaves(), 1);
+
+                let center = Grid2D::new(100.5, 100.5);
+                let mut near = tree.neighbor_search(&center, 3.0);
+                near.sort();
+                assert_eq!(near, vec![99, 100, 101, 102]);
+
+                let mut within = vec![];
+                let _ = tree.for_each_within(&center, 3.0, |&i, _, _| {
+                    within.push(i);
+                    ControlFlow::<()>::Continue(())
+                });
+                within.sort();
+                assert_eq!(within, near);
+
+                let nearest = tree.k_nearest(&center, 4);
+                let mut ids: Vec<usize> = nearest.iter().map(|&(i, _)| i).collect();
+                ids.sort();
+                assert_eq!(ids, near);
+                assert_eq!(tree.nearest(&Grid2D::new(0.0, 0.0)), Some((0, 0.0)));
+
+                let pairs = tree.pairs_within(1.5);
+                assert_eq!(pairs.len(), num_point - 1);
+                assert!(pairs.iter().all(|&(i, j)| j == i + 1));
+            })
+            .unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn split_policies() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 2000;
+
+        // Clusters along a strip a hundred times longer than it is wide.
+        let mut vec = Points2D::new();
+        for _ in 0..num_point {
+            let cluster = rng.gen_range(0..5) as f64;
+            let x_r = 20.0 * cluster + rng.gen::<f64>();
+            let y_r = rng.gen::<f64>();
+            vec.push(x_r, y_r);
+        }
+
+        let policies = [
+            SplitPolicy::RoundRobin,
+            SplitPolicy::MaxSpread,
+            SplitPolicy::SlidingMidpoint,
+        ];
+        for policy in policies {
+            let mut tree = KDTree::construct_balanced_kd_tree_with_options(
+                &vec,
+                BuildOptions {
+                    split_policy: policy,
+                    ..Default::default()
+                },
+            );
+            if policy != SplitPolicy::RoundRobin {
+                assert_eq!(tree.nodes[tree.root.unwrap()].axis, 0);
             }
-            _ => {
-                if self.position.y > point.y {
-                    match &mut self.left {
-                        Some(left_node) => {
-                            depth += 1;
-                            left_node.insert(point, depth, id);
-                        }
-                        None => {
-                            let node = Self::new(point, id);
-                            self.left = Some(Box::new(node));
-                        }
-                    }
-                } else {
-                    match &mut self.right {
-                        Some(right_node) => {
-                            depth += 1;
-                            right_node.insert(point, depth, id);
-                        }
-                        None => {
-                            let node = Self::new(point, id);
-                            self.right = Some(Box::new(node));
-                        }
+            for i in (0..num_point).step_by(7) {
+                vec.points[i].x += 0.5;
+                assert!(tree.relocate(i, &vec.points[i]));
+            }
+            for _ in 0..100 {
+                vec.push(100.0 * rng.gen::<f64>(), rng.gen::<f64>());
+                tree.insert(vec.points.last().unwrap());
+            }
+
+            for _ in 0..20 {
+                let center = Grid2D::new(100.0 * rng.gen::<f64>(), rng.gen::<f64>());
+                let radius = 0.5;
+                let mut near = tree.neighbor_search(&center, radius);
+                near.sort();
+                let expected: Vec<usize> = (0..vec.points.len())
+                    .filter(|&i| vec.points[i].distance_square(&center).sqrt() < radius)
+                    .collect();
+                assert_eq!(near, expected);
+                assert_eq!(tree.count_within(&center, radius), expected.len());
+
+                let nearest = tree.nearest(&center).unwrap();
+                let r_min = vec
+                    .points
+                    .iter()
+                    .map(|p| p.distance_square(&center).sqrt())
+                    .fold(f64::INFINITY, f64::min);
+                assert_eq!(nearest.1, r_min);
+            }
+
+            let pairs = tree.pairs_within(0.05);
+            let mut expected = vec![];
+            for i in 0..vec.points.len() {
+                for j in i + 1..vec.points.len() {
+                    if vec.points[i].distance_square(&vec.points[j]).sqrt() < 0.05 {
+                        expected.push((i, j));
                     }
                 }
             }
+            assert_eq!(pairs, expected);
+            vec.points.truncate(num_point);
+            for i in (0..num_point).step_by(7) {
+                vec.points[i].x -= 0.5;
+            }
         }
-    }
 
-    #[allow(dead_code)]
-    fn create_kd_tree(&mut self, vec: &Points2D) -> Self {
-        let depth = 0;
-        for i in 1..vec.points.len() {
-            self.insert(&vec.points[i], depth, i);
+        // Points that coincide still split evenly.
+        let mut same = Points2D::new();
+        for _ in 0..5000 {
+            same.push(1.0, 2.0);
+        }
+        let balanced_depth = (5000_f64).log2().ceil() as i32;
+        for policy in policies {
+            let mut tree = KDTree::construct_balanced_kd_tree_with_options(
+                &same,
+                BuildOptions {
+                    split_policy: policy,
+                    ..Default::default()
+                },
+            );
+            assert!(tree.depth() <= balanced_depth);
+            assert_eq!(
+                tree.neighbor_search(&Grid2D::new(1.0, 2.0), 0.1).len(),
+                5000
+            );
+            tree.rebuild();
+            assert!(tree.depth() <= balanced_depth);
         }
-        self.clone()
     }
 
-    #[allow(dead_code)]
-    pub fn depth(&self) -> i32 {
-        match (&self.left, &self.right) {
-            (Some(left), Some(right)) => 1 + (left.depth()).max(right.depth()),
-            (None, None) => 0,
-            (None, Some(right)) => 1 + right.depth(),
-            (Some(left), None) => 1 + left.depth(),
+    #[test]
+    fn leaf_buckets() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 5000;
+
+        let mut vec = Points2D::new();
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
         }
-    }
 
-    #[allow(dead_code)]
-    pub fn construct_kd_tree(vec: &Points2D) -> KDTree {
-        let mut tree = KDTree::new(&Grid2D::new(vec.points[0].x, vec.points[0].y), 0);
-        tree.create_kd_tree(vec)
-    }
+        for bucket_size in [2, 8, 32] {
+            let mut plain = KDTree::construct_balanced_kd_tree(&vec);
+            let mut tree = KDTree::construct_balanced_kd_tree_with_options(
+                &vec,
+                BuildOptions {
+                    bucket_size,
+                    ..Default::default()
+                },
+            );
+            let largest = tree.nodes.iter().map(|node| node.bucket).max().unwrap();
+            assert!(1 < largest && largest <= bucket_size);
 
-    #[allow(dead_code)]
-    pub fn size(&self) -> i32 {
-        match (&self.left, &self.right) {
-            (Some(left), Some(right)) => 1 + left.size() + right.size(),
-            (None, None) => 1,
-            (None, Some(right)) => 1 + right.size(),
-            (Some(left), None) => 1 + left.size(),
+            for id in (0..num_point).step_by(11) {
+                assert!(plain.remove(id));
+                assert!(tree.remove(id));
+            }
+            for id in (1..num_point).step_by(13) {
+                let point = Grid2D::new(rng.gen::<f64>(), rng.gen::<f64>());
+                plain.relocate(id, &point);
+                tree.relocate(id, &point);
+            }
+            for _ in 0..200 {
+                let point = Grid2D::new(rng.gen::<f64>(), rng.gen::<f64>());
+                assert_eq!(tree.insert(&point), plain.insert(&point));
+            }
+
+            for _ in 0..100 {
+                let center = Grid2D::new(
+                    2.0 * (rng.gen::<f64>() - 0.5),
+                    2.0 * (rng.gen::<f64>() - 0.5),
+                );
+                let radius = 0.1;
+                let mut near = tree.neighbor_search(&center, radius);
+                let mut expected = plain.neighbor_search(&center, radius);
+                near.sort();
+                expected.sort();
+                assert_eq!(near, expected);
+                assert_eq!(
+                    tree.neighbor_search_with_distances(&center, radius, NeighborOrder::Id),
+                    plain.neighbor_search_with_distances(&center, radius, NeighborOrder::Id)
+                );
+                assert_eq!(tree.k_nearest(&center, 10), plain.k_nearest(&center, 10));
+                assert_eq!(tree.nearest(&center), plain.nearest(&center));
+
+                let min = Grid2D::new(center.x - 0.1, center.y - 0.2);
+                let mut found = tree.range_query(&min, &center);
+                let mut expected = plain.range_query(&min, &center);
+                found.sort();
+                expected.sort();
+                assert_eq!(found, expected);
+            }
+            assert_eq!(tree.pairs_within(0.02), plain.pairs_within(0.02));
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn depth_and_size() {
+    fn k_nearest_approx_bound() {
         use rand::prelude::*;
         let seed: [u8; 32] = [1; 32];
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
-        let num_point: usize = 600;
+        let num_point: usize = 10_000;
 
         let mut vec = Points2D::new();
-
         for _ in 0..num_point {
             let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
             let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
             vec.push(x_r, y_r);
         }
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+        let bucketed = KDTree::construct_balanced_kd_tree_with_options(
+            &vec,
+            BuildOptions {
+                bucket_size: 16,
+                ..Default::default()
+            },
+        );
 
-        let tree = KDTree::construct_kd_tree(&mut vec);
+        for _ in 0..100 {
+            let center = Grid2D::new(
+                2.0 * (rng.gen::<f64>() - 0.5),
+                2.0 * (rng.gen::<f64>() - 0.5),
+            );
+            let k = 10;
+            let mut exact: Vec<f64> = vec
+                .points
+                .iter()
+                .map(|p| p.distance_square(&center).sqrt())
+                .collect();
+            exact.sort_by(|a, b| a.total_cmp(b));
 
-        assert_eq!(tree.depth(), 20);
-        assert_eq!(tree.size(), 600);
+            let distances = |near: &[(usize, f64)]| near.iter().map(|a| a.1).collect::<Vec<_>>();
+            assert_eq!(
+                distances(&tree.k_nearest_approx(&center, k, 0.0)),
+                exact[..k]
+            );
+            for eps in [0.1, 1.0, 3.0] {
+                for near in [
+                    tree.k_nearest_approx(&center, k, eps),
+                    bucketed.k_nearest_approx(&center, k, eps),
+                ] {
+                    assert_eq!(near.len(), k);
+                    for (i, &(id, r)) in near.iter().enumerate() {
+                        assert_eq!(r, vec.points[id].distance_square(&center).sqrt());
+                        assert!(r <= (1.0 + eps) * exact[i]);
+                    }
+                }
+            }
+        }
+        assert!(tree
+            .k_nearest_approx(&Grid2D::new(0.0, 0.0), 0, 1.0)
+            .is_empty());
     }
 
     #[test]
-    fn search_1() {
+    fn annulus_search_brute_force() {
         use rand::prelude::*;
         let seed: [u8; 32] = [1; 32];
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
-        let num_point: usize = 10;
+        let num_point: usize = 10_000;
 
         let mut vec = Points2D::new();
+        for _ in 0..num_point {
+            let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push(x_r, y_r);
+        }
+        let mut tree = KDTree::construct_kd_tree(&vec);
+        for id in (0..num_point).step_by(5) {
+            tree.remove(id);
+        }
+        let bucketed = KDTree::construct_balanced_kd_tree_with_options(
+            &vec,
+            BuildOptions {
+                bucket_size: 8,
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..100 {
+            let center = Grid2D::new(
+                2.0 * (rng.gen::<f64>() - 0.5),
+                2.0 * (rng.gen::<f64>() - 0.5),
+            );
+            let r_min = 0.3 * rng.gen::<f64>();
+            let r_max = r_min + 0.2 * rng.gen::<f64>();
+            let shell = |i: usize| {
+                let r = vec.points[i].distance_square(&center).sqrt();
+                r_min <= r && r < r_max
+            };
+
+            let mut found = tree.annulus_search(&center, r_min, r_max);
+            found.sort();
+            let expected: Vec<usize> = (0..num_point).filter(|&i| i % 5 != 0 && shell(i)).collect();
+            assert_eq!(found, expected);
+
+            let mut found = bucketed.annulus_search(&center, r_min, r_max);
+            found.sort();
+            let expected: Vec<usize> = (0..num_point).filter(|&i| shell(i)).collect();
+            assert_eq!(found, expected);
+        }
+
+        let center = Grid2D::new(0.1, -0.2);
+        let mut found = bucketed.annulus_search(&center, 0.0, 0.25);
+        let mut expected = bucketed.neighbor_search(&center, 0.25);
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn region_query_brute_force() {
+        use rand::prelude::*;
+        let seed: [u8; 32] = [1; 32];
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
+        let num_point: usize = 10_000;
 
+        let mut vec = Points2D::new();
         for _ in 0..num_point {
             let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
             let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
             vec.push(x_r, y_r);
         }
+        let mut tree = KDTree::construct_balanced_kd_tree(&vec);
+        for id in (0..num_point).step_by(7) {
+            tree.remove(id);
+        }
+        let bucketed = KDTree::construct_balanced_kd_tree_with_options(
+            &vec,
+            BuildOptions {
+                bucket_size: 8,
+                ..Default::default()
+            },
+        );
 
-        let tree = KDTree::construct_kd_tree(&mut vec);
-        let center = Grid2D { x: 0.0, y: 0.0 };
-        let radius = 0.4;
-        let near = tree.neighbor_search(&center, radius);
+        let hexagon: Vec<Grid2D> = (0..6)
+            .map(|i| {
+                let angle = 0.3 + i as f64 * std::f64::consts::PI / 3.0;
+                Grid2D::new(0.1 + 0.5 * angle.cos(), -0.2 + 0.5 * angle.sin())
+            })
+            .collect();
+        let clockwise_triangle = [
+            Grid2D::new(-0.9, -0.9),
+            Grid2D::new(0.0, 0.8),
+            Grid2D::new(0.7, -0.5),
+        ];
+        let regions: Vec<Box<dyn Region<2>>> = vec![
+            Box::new(ConvexPolygon::new(&hexagon)),
+            Box::new(ConvexPolygon::new(&clockwise_triangle)),
+            Box::new(HalfPlane::new(Grid2D::new(1.0, 2.0), 0.3)),
+            Box::new(HalfPlane::new(Grid2D::new(0.0, -1.0), 0.5)),
+            Box::new(Disk::new(Grid2D::new(0.2, 0.1), 0.4)),
+            Box::new(Disk::new(Grid2D::new(0.0, 0.0), 10.0)),
+        ];
 
-        assert_eq!(near, [1 as usize, 9 as usize].to_vec());
+        for region in &regions {
+            let inside = |i: usize| region.contains(&vec.points[i].coordinates());
+
+            let mut found = tree.region_query(region.as_ref());
+            found.sort();
+            let expected: Vec<usize> = (0..num_point)
+                .filter(|&i| i % 7 != 0 && inside(i))
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(found, expected);
+
+            let mut found = bucketed.region_query(region.as_ref());
+            found.sort();
+            let expected: Vec<usize> = (0..num_point).filter(|&i| inside(i)).collect();
+            assert_eq!(found, expected);
+        }
+
+        let disk = Disk::new(Grid2D::new(0.1, -0.2), 0.3);
+        assert_eq!(
+            disk.classify(&[0.0, -0.3], &[0.2, -0.1]),
+            Containment::Inside
+        );
+        assert_eq!(
+            disk.classify(&[0.5, 0.5], &[0.6, 0.6]),
+            Containment::Outside
+        );
+        let triangle = ConvexPolygon::new(&clockwise_triangle);
+        assert!(triangle.contains(&[0.0, 0.0]));
+        assert!(!triangle.contains(&[0.0, -0.9]));
+        assert_eq!(
+            triangle.classify(&[-0.1, -0.1], &[0.1, 0.1]),
+            Containment::Inside
+        );
+        assert_eq!(
+            triangle.classify(&[0.8, 0.5], &[f64::INFINITY, f64::INFINITY]),
+            Containment::Outside
+        );
     }
 
     #[test]
-    fn search_2() {
+    fn search_with_items() {
         use rand::prelude::*;
         let seed: [u8; 32] = [1; 32];
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
@@ -325,40 +3540,59 @@ mod tests {
             vec.push(x_r, y_r);
         }
 
-        let tree = KDTree::construct_kd_tree(&mut vec);
+        let names: Vec<String> = (0..num_point).map(|i| format!("p{}", i)).collect();
         let center = Grid2D { x: 0.4, y: 0.3 };
         let radius = 0.5;
-        let near = tree.neighbor_search(&center, radius);
 
+        let tree = KDTree::construct_kd_tree_with_items(&vec, names.clone());
         assert_eq!(
-            near,
-            [1 as usize, 2 as usize, 6 as usize, 9 as usize, 5 as usize].to_vec()
+            tree.neighbor_search(&center, radius),
+            ["p1", "p2", "p6", "p9", "p5"]
         );
+
+        let balanced = KDTree::construct_balanced_kd_tree_with_items(&vec, names);
+        let mut near = balanced.neighbor_search(&center, radius);
+        near.sort();
+        assert_eq!(near, ["p1", "p2", "p5", "p6", "p9"]);
+        assert_eq!(balanced.nearest(&center), tree.nearest(&center));
+        assert_eq!(balanced.k_nearest(&center, 3), tree.k_nearest(&center, 3));
     }
 
     #[test]
-    fn count_leaves() {
+    fn search_3d() {
         use rand::prelude::*;
         let seed: [u8; 32] = [1; 32];
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(seed);
-        let num_point: usize = 10;
+        let num_point: usize = 1000;
 
-        let mut vec = Points2D::new();
+        let mut vec: Vec<[f64; 3]> = vec![];
 
         for _ in 0..num_point {
             let x_r = 2.0 * (rng.gen::<f64>() - 0.5);
             let y_r = 2.0 * (rng.gen::<f64>() - 0.5);
-            vec.push(x_r, y_r);
+            let z_r = 2.0 * (rng.gen::<f64>() - 0.5);
+            vec.push([x_r, y_r, z_r]);
         }
 
-        let tree = KDTree::construct_kd_tree(&mut vec);
+        let center = [0.1, -0.3, 0.2];
+        let radius = 0.4;
+        let mut expected: Vec<usize> = (0..num_point)
+            .filter(|&i| distance_square(&vec[i], &center).sqrt() < radius)
+            .collect();
+        expected.sort();
 
-        let center = Grid2D { x: 0.4, y: 0.3 };
-        let radius = 0.5;
-        let mut near = vec![0 as usize; 0];
-        tree.search_points_id(&center, radius, &mut near, 0);
+        for tree in [
+            KDTree::construct_kd_tree(&vec),
+            KDTree::construct_balanced_kd_tree(&vec),
+        ] {
+            assert_eq!(tree.size(), num_point as i32);
+            let mut near = tree.neighbor_search(&center, radius);
+            near.sort();
+            assert_eq!(near, expected);
 
-        assert_eq!(tree.number_of_leaves(), 4);
+            let nearest = tree.nearest(&center).unwrap();
+            assert_eq!(tree.k_nearest(&center, 1), vec![nearest]);
+        }
     }
 
     #[test]
@@ -431,4 +3665,90 @@ mod tests {
         }
         assert_eq!(0, 1);
     }
+
+    #[test]
+    fn approx_speed_test() {
+        use rand::Rng;
+        use std::time::Instant;
+
+        let mut rnd = rand::thread_rng();
+        let mut vec = Points2D::new();
+        for _ in 0..10_000_000 {
+            vec.push(rnd.gen::<f64>(), rnd.gen::<f64>());
+        }
+        let centers: Vec<Grid2D> = (0..10_000)
+            .map(|_| Grid2D::new(rnd.gen::<f64>(), rnd.gen::<f64>()))
+            .collect();
+        let tree = KDTree::construct_balanced_kd_tree(&vec);
+
+        let start = Instant::now();
+        let exact: Vec<Vec<(usize, f64)>> = centers.iter().map(|c| tree.k_nearest(c, 8)).collect();
+        let end = start.elapsed();
+        println!("exact, {}.{:03}", end.as_secs(), end.subsec_millis());
+
+        for eps in [0.0, 0.1, 0.5, 1.0, 2.0] {
+            let start = Instant::now();
+            let approx: Vec<Vec<(usize, f64)>> = centers
+                .iter()
+                .map(|c| tree.k_nearest_approx(c, 8, eps))
+                .collect();
+            let end = start.elapsed();
+            println!("{}, {}.{:03}", eps, end.as_secs(), end.subsec_millis());
+
+            for (approx, exact) in approx.iter().zip(&exact) {
+                assert_eq!(approx.len(), exact.len());
+                for (a, e) in approx.iter().zip(exact) {
+                    assert!(a.1 <= (1.0 + eps) * e.1 + 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn bucket_speed_test() {
+        use rand::Rng;
+        use std::time::Instant;
+
+        let mut rnd = rand::thread_rng();
+        let mut vec = Points2D::new();
+        for _ in 0..200_000 {
+            vec.push(rnd.gen::<f64>(), rnd.gen::<f64>());
+        }
+        let centers: Vec<Grid2D> = (0..10_000)
+            .map(|_| Grid2D::new(rnd.gen::<f64>(), rnd.gen::<f64>()))
+            .collect();
+
+        for bucket_size in [1, 2, 4, 8, 16, 32, 64] {
+            let tree = KDTree::construct_balanced_kd_tree_with_options(
+                &vec,
+                BuildOptions {
+                    bucket_size,
+                    ..Default::default()
+                },
+            );
+
+            let start = Instant::now();
+            for center in &centers {
+                tree.neighbor_search(center, 0.005);
+            }
+            let search = start.elapsed();
+
+            let start = Instant::now();
+            for center in &centers {
+                tree.k_nearest(center, 8);
+            }
+            let k_nearest = start.elapsed();
+
+            println!(
+                "{}, {}.{:03}, {}.{:03}",
+                bucket_size,
+                search.as_secs(),
+                search.subsec_millis(),
+                k_nearest.as_secs(),
+                k_nearest.subsec_millis()
+            );
+        }
+        assert_eq!(0, 1);
+    }
 }
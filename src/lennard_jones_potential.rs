@@ -1,4 +1,5 @@
 use super::kd_tree;
+use std::ops::ControlFlow;
 
 impl kd_tree::Points2D {
     #[allow(dead_code)]
@@ -77,11 +78,10 @@ impl kd_tree::Points2D {
         let sigma8 = sigma4 * sigma4;
         let sigma6 = sigma2 * sigma4;
         let sigma12 = sigma8 * sigma4;
-        let near = tree.neighbor_search(&self.points[index], radius);
-        for k in near.iter() {
-            if index != *k {
-                let dx = self.points[index].x - self.points[*k].x;
-                let dy = self.points[index].y - self.points[*k].y;
+        let _ = tree.for_each_within(&self.points[index], radius, |&k, _, _| {
+            if index != k {
+                let dx = self.points[index].x - self.points[k].x;
+                let dy = self.points[index].y - self.points[k].y;
                 let r = (dx * dx + dy * dy).sqrt();
                 let r2 = r * r;
                 let r4 = r2 * r2;
@@ -91,7 +91,8 @@ impl kd_tree::Points2D {
                 f_x += 4.0 * epsilon * (12.0 * sigma12 / r13 - 6.0 * sigma6 / r7) * dx;
                 f_y += 4.0 * epsilon * (12.0 * sigma12 / r13 - 6.0 * sigma6 / r7) * dy;
             }
-        }
+            ControlFlow::<()>::Continue(())
+        });
         for i in 0..boundary.points.len() {
             let dx = self.points[index].x - boundary.points[i].x;
             let dy = self.points[index].y - boundary.points[i].y;
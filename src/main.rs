@@ -112,7 +112,7 @@ fn main() {
 
     let max_counter = 5_000;
 
-    let mut tree = kd_tree::KDTree::construct_kd_tree(&vec);
+    let mut tree = kd_tree::KDTree::construct_balanced_kd_tree(&vec);
 
     for i in 0..num_boundary {
         let t = 2.0 * std::f64::consts::PI * i as f64 / num_boundary as f64;
@@ -124,7 +124,7 @@ fn main() {
         for _ in 0..10 {
             vec.euler_step_by_near_points(&boundary, &tree, radius);
             //vec.euler_step(&boundary);
-            tree = kd_tree::KDTree::construct_kd_tree(&vec);
+            tree.update_positions(&vec);
         }
         println!("{} / {}", i, max_counter - 1);
     }